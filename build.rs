@@ -0,0 +1,23 @@
+//! Builds the LD_PRELOAD library from `src/interceptor.c` on Linux, so it can
+//! be embedded in the crate (see `src/preload.rs`).
+
+fn main() {
+    println!("cargo::rerun-if-changed=src/interceptor.c");
+    println!("cargo::rustc-check-cfg=cfg(rim_preload)");
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("linux") {
+        return;
+    }
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let library = out_dir.join("librim_preload.so");
+    let compiler = cc::Build::new().get_compiler();
+    let status = compiler
+        .to_command()
+        .args(["-shared", "-fPIC", "-O2", "-Wall", "-Werror", "-o"])
+        .arg(&library)
+        .arg("src/interceptor.c")
+        .arg("-ldl")
+        .status()
+        .expect("Error running the C compiler");
+    assert!(status.success(), "Error building src/interceptor.c");
+    println!("cargo::rustc-cfg=rim_preload");
+}
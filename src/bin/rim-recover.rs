@@ -1,6 +1,15 @@
 use clap::Parser;
-use rim::{config::Config, App};
-use std::path::PathBuf;
+use rim::{
+    config::{Config, Storage},
+    human::{format_relative_time, format_size},
+    metadata_db::{ListFilter, TrashEntry},
+    output::{print_entries, print_json_object, Format},
+    App,
+};
+use std::{
+    io::{IsTerminal, Write},
+    path::PathBuf,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -10,16 +19,214 @@ use std::path::PathBuf;
     about = "Recycle bin for the command line"
 )]
 struct Opts {
-    filename: Option<String>,
+    #[arg(
+        value_name = "ID|HASH|PATH",
+        help = "Entry id, blake3 prefix (at least 4 hex digits) or original path of the file to recover"
+    )]
+    target: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "When several versions of the path are in the trash, recover the Nth newest"
+    )]
+    revision: u32,
+
+    #[arg(
+        long,
+        value_name = "ID",
+        conflicts_with = "target",
+        help = "Recover everything one command run under rim-wrap deleted, each file as it was before the command"
+    )]
+    operation: Option<i64>,
 
     #[arg(short, long)]
     config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Import files deleted from desktop file managers (FreeDesktop.org trash) first"
+    )]
+    import: bool,
+
+    #[arg(long, value_enum, default_value = "table", help = "Output format")]
+    format: Format,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read the encryption key from this file instead of asking for the passphrase"
+    )]
+    key_file: Option<PathBuf>,
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
-    let config = std::rc::Rc::new(Config::load(opts.config).expect("Error opening config file"));
-    let app = App::new(config).unwrap();
-    let id = opts.filename.unwrap().parse::<i64>().unwrap();
-    app.recover_file(id).unwrap();
+    let mut config = Config::load(opts.config).expect("Error opening config file");
+    if let Some(key_file) = opts.key_file {
+        config
+            .encryption
+            .get_or_insert_with(Default::default)
+            .key_file = Some(key_file);
+    }
+    let import = opts.import || config.storage == Storage::Freedesktop;
+    let encrypt_metadata = config
+        .encryption
+        .as_ref()
+        .is_some_and(|e| e.encrypt_metadata);
+    let app = App::new(std::rc::Rc::new(config)).unwrap();
+    let mut imported: Option<usize> = None;
+    if import {
+        imported = Some(app.import_freedesktop_trash().unwrap());
+    }
+    let mut recovered = vec![];
+    let mut failed = false;
+    // encrypted paths have to be readable to find the target by path, and
+    // to tell which entries of an operation are versions of the same file
+    if encrypt_metadata && (opts.target.is_some() || opts.operation.is_some()) {
+        match app.unlock() {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("rim-recover: {}", e);
+                std::process::exit(1);
+            }
+            _ => (),
+        }
+    }
+    if let Some(target) = opts.target {
+        let result = choose_entry(&app, &target, opts.revision).and_then(|id| app.recover_file(id));
+        match result {
+            Ok(entry) => recovered.push(entry),
+            Err(e) => {
+                eprintln!("rim-recover: {}: {}", target, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(operation) = opts.operation {
+        let filter = ListFilter {
+            operation: Some(operation),
+            ..Default::default()
+        };
+        // newest first, so directories come back before what was in them
+        let mut entries = app.list(&filter).unwrap_or_else(|e| {
+            eprintln!("rim-recover: {}", e);
+            std::process::exit(1);
+        });
+        // a file the command overwrote several times, or overwrote and then
+        // deleted, comes back as it was before the command: the oldest
+        // version. The others stay in the trash.
+        let mut seen = std::collections::HashSet::new();
+        entries.reverse();
+        entries.retain(|entry| seen.insert(entry.metadata.original_path.clone()));
+        entries.reverse();
+        if entries.is_empty() {
+            eprintln!(
+                "rim-recover: Nothing from operation {} in the trash",
+                operation
+            );
+            std::process::exit(1);
+        }
+        for entry in entries {
+            match app.recover_file(entry.id) {
+                Ok(entry) => recovered.push(entry),
+                Err(e) => {
+                    eprintln!("rim-recover: {}: {}", entry.metadata.original_path, e);
+                    failed = true;
+                }
+            }
+        }
+    }
+    match opts.format {
+        Format::Table => {
+            if let (true, Some(n)) = (opts.import, imported) {
+                println!("Imported {} entries from the FreeDesktop.org trash", n);
+            }
+            for entry in recovered.iter() {
+                println!("Recovered '{}'", entry.metadata.original_path);
+            }
+        }
+        Format::Json => {
+            let mut document = serde_json::Map::new();
+            if let Some(n) = imported {
+                document.insert("imported".to_string(), n.into());
+            }
+            document.insert(
+                "recovered".to_string(),
+                serde_json::to_value(&recovered).unwrap(),
+            );
+            print_json_object(document);
+        }
+        format => print_entries(format, "recovered", &recovered),
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Resolves `target` to a single entry id. Versions of the same path are
+/// picked by `revision` (1 is the newest); otherwise the user is asked to
+/// pick one when several entries match.
+fn choose_entry(app: &App, target: &str, revision: u32) -> Result<i64, std::io::Error> {
+    let cwd = std::env::current_dir()?;
+    let candidates = app.find_entries(target, &cwd)?;
+    let same_path = candidates
+        .iter()
+        .all(|e| e.metadata.original_path == candidates[0].metadata.original_path);
+    match candidates.len() {
+        0 => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No matching file in trash",
+        )),
+        n if same_path => match candidates.get(revision as usize - 1) {
+            Some(entry) => Ok(entry.id),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Only {} version(s) in the trash", n),
+            )),
+        },
+        n => {
+            eprintln!("Several trashed files match '{}':", target);
+            print_candidates(&candidates);
+            if !std::io::stdin().is_terminal() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Ambiguous; pass one of the ids above",
+                ));
+            }
+            loop {
+                eprint!("Which one should be recovered? [1-{}] ", n);
+                let _ = std::io::stderr().flush();
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer)? == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Interrupted,
+                        "No choice made",
+                    ));
+                }
+                match answer.trim().parse::<usize>() {
+                    Ok(i) if (1..=n).contains(&i) => return Ok(candidates[i - 1].id),
+                    _ => eprintln!("Please enter a number between 1 and {}", n),
+                }
+            }
+        }
+    }
+}
+
+fn print_candidates(candidates: &[TrashEntry]) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    for (i, entry) in candidates.iter().enumerate() {
+        eprintln!(
+            "  [{}] id {}  {}  {}  {}  {}",
+            i + 1,
+            entry.id,
+            &entry.metadata.blake3sum[..7.min(entry.metadata.blake3sum.len())],
+            format_relative_time(entry.created_at, now),
+            format_size(entry.metadata.file_size),
+            entry.metadata.original_path
+        );
+    }
 }
@@ -1,5 +1,191 @@
-use rim::{config::Config, metadata_db::MetadataDB, App};
+use clap::Parser;
+use rim::{config::Config, human::format_size, metadata_db::ListFilter, preload, App};
+use std::{
+    ffi::OsString,
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::{exit, Command},
+    rc::Rc,
+};
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "rim-wrap",
+    version = "0.1.0",
+    author = "Zelly Snyder",
+    about = "Run a command with the files it deletes going to the trash"
+)]
+struct Opts {
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Also keep copies of files the command overwrites (preload_overwrites in the config)"
+    )]
+    overwrites: bool,
+
+    #[arg(
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        value_name = "COMMAND",
+        help = "The command to run, with its arguments, e.g. -- make clean"
+    )]
+    command: Vec<OsString>,
+}
 
 fn main() {
-    todo!()
+    let opts: Opts = Opts::parse();
+    let config_path = opts.config.clone().or_else(Config::find);
+    let config = Rc::new(Config::load(opts.config).expect("Error opening config file"));
+    let app = App::new(config.clone()).unwrap();
+    let library = preload::library_path().unwrap_or_else(|e| {
+        eprintln!("rim-wrap: {}", e);
+        exit(1)
+    });
+    let command_line = opts
+        .command
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let operation = app.begin_operation(&command_line).unwrap_or_else(|e| {
+        eprintln!("rim-wrap: {}", e);
+        exit(1)
+    });
+
+    let mut command = Command::new(&opts.command[0]);
+    command.args(&opts.command[1..]);
+    let mut preload = library.into_os_string();
+    if let Some(others) = std::env::var_os("LD_PRELOAD").filter(|p| !p.is_empty()) {
+        preload.push(":");
+        preload.push(others);
+    }
+    command.env("LD_PRELOAD", preload);
+    command.env("RIM_OPERATION", operation.to_string());
+    if opts.overwrites || config.preload_overwrites {
+        command.env("RIM_PRELOAD_OVERWRITES", "1");
+    }
+    // the library can skip running rim for these altogether; it compares
+    // them with resolved paths, so they are resolved as well
+    let exclude = std::iter::once(&config.trashdir)
+        .chain(config.preload_exclude.iter())
+        .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.clone()))
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(":");
+    command.env("RIM_PRELOAD_EXCLUDE", exclude);
+    if let Some(path) = config_path {
+        let cwd = std::env::current_dir().expect("Error getting working directory");
+        command.env("RIM_CONFIG", cwd.join(path));
+    }
+    // prefer the rim this was installed with over whatever is on $PATH
+    if let Some(rim) = std::env::current_exe()
+        .map(|exe| exe.with_file_name("rim"))
+        .ok()
+        .filter(|rim| rim.exists())
+    {
+        command.env("RIM_BIN", rim);
+    }
+
+    let mut child = command.spawn().unwrap_or_else(|e| {
+        eprintln!("rim-wrap: {}: {}", opts.command[0].to_string_lossy(), e);
+        exit(if e.kind() == std::io::ErrorKind::NotFound {
+            127
+        } else {
+            126
+        })
+    });
+    // ^C is for the child; stay around to report what it deleted
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+    let status = child.wait().expect("Error waiting for the command");
+
+    let filter = ListFilter {
+        operation: Some(operation),
+        ..Default::default()
+    };
+    match app.list(&filter) {
+        Ok(entries) if entries.is_empty() => {
+            eprintln!("rim-wrap: '{}' didn't trash anything", command_line);
+        }
+        Ok(entries) => {
+            let size: u64 = entries.iter().map(|e| e.metadata.file_size).sum();
+            eprintln!(
+                "rim-wrap: '{}' put {} entr{} ({}) in the trash",
+                command_line,
+                entries.len(),
+                if entries.len() == 1 { "y" } else { "ies" },
+                format_size(size)
+            );
+            eprintln!(
+                "rim-wrap: see them with `rim list --operation {}`",
+                operation
+            );
+            eprintln!(
+                "rim-wrap: undo with `rim-recover --operation {}`",
+                operation
+            );
+        }
+        Err(e) => eprintln!("rim-wrap: {}", e),
+    }
+    exit(match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    })
+}
+
+/// Quotes `arg` for a POSIX shell, so the command line shown for an
+/// operation can be told apart from, and pasted as, the one which ran.
+/// Bytes which aren't UTF-8 are written as `$'\xNN'`, as bash and zsh
+/// understand.
+fn quote(arg: &std::ffi::OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = arg.as_bytes();
+    let plain = |b: &u8| b.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(b);
+    if bytes.is_empty() {
+        return "''".to_string();
+    }
+    if bytes.iter().all(plain) {
+        return arg.to_string_lossy().into_owned();
+    }
+    let mut quoted = String::new();
+    for chunk in bytes.utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted.push('\'');
+            quoted.push_str(&chunk.valid().replace('\'', r"'\''"));
+            quoted.push('\'');
+        }
+        if !chunk.invalid().is_empty() {
+            quoted.push_str("$'");
+            for b in chunk.invalid() {
+                quoted.push_str(&format!("\\x{:02x}", b));
+            }
+            quoted.push('\'');
+        }
+    }
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_quote() {
+        let quote = |arg: &[u8]| quote(std::ffi::OsStr::from_bytes(arg));
+        assert_eq!(quote(b"rm"), "rm");
+        assert_eq!(quote(b"-rf"), "-rf");
+        assert_eq!(quote(b"src/main.o"), "src/main.o");
+        assert_eq!(quote(b"a b"), "'a b'");
+        assert_eq!(quote(b""), "''");
+        assert_eq!(quote(b"it's"), r"'it'\''s'");
+        assert_eq!(quote(b"*.o"), "'*.o'");
+        assert_eq!(quote(b"caf\xe9.txt"), r"'caf'$'\xe9''.txt'");
+    }
 }
@@ -1,63 +1,881 @@
-use clap::Parser;
-use rim::{config::Config, App};
-use std::{path::PathBuf, process::Command, rc::Rc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rim::{
+    config::{Config, WhenFull},
+    fs::find_other_device,
+    human::{format_relative_time, format_size, parse_duration, parse_time},
+    metadata_db::{ListFilter, SortKey},
+    output::{print_entries, Format},
+    preload, protect, purge, App, RecycleOptions,
+};
+use std::{
+    io::{IsTerminal, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
     name = "rim",
     version = "0.1.0",
     author = "Zelly Snyder",
-    about = "Recycle bin for the command line"
+    about = "Recycle bin for the command line",
+    override_usage = "rim [OPTIONS] [FILE]...\n       rim [--config <CONFIG>] <SUBCOMMAND> [ARGS]...",
+    after_help = "Subcommands are list, maintenance, purge, pin, unpin, extend, recover and \
+                  wrap, plus any rim-<name> program next to rim or on $PATH. \
+                  Use `rim -- <FILE>` to recycle a file named like a subcommand."
 )]
 struct Opts {
-    #[arg(help = "File or directory to recycle")]
-    filename: PathBuf,
+    #[arg(value_name = "FILE", help = "Files or directories to recycle")]
+    filenames: Vec<PathBuf>,
 
-    #[arg(short, long)]
+    #[arg(short, long, help = "Explain what is being done")]
     verbose: bool,
 
     #[arg(
         short,
         long,
+        short_alias = 'R',
         default_value = "false",
         help = "Recursively recycle directories"
     )]
     recursive: bool,
 
-    #[arg(short, long)]
+    #[arg(
+        short = 'd',
+        long = "dir",
+        help = "Recycle empty directories without --recursive"
+    )]
+    empty_dirs: bool,
+
+    #[arg(
+        short,
+        long,
+        overrides_with_all = ["prompt_always", "prompt_once", "interactive"],
+        help = "Ignore nonexistent files and arguments, never prompt"
+    )]
     force: bool,
 
+    #[arg(
+        short = 'i',
+        overrides_with_all = ["force", "prompt_once", "interactive"],
+        help = "Prompt before every removal"
+    )]
+    prompt_always: bool,
+
+    #[arg(
+        short = 'I',
+        overrides_with_all = ["force", "prompt_always", "interactive"],
+        help = "Prompt once before recycling more than three files, or when recycling recursively"
+    )]
+    prompt_once: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always",
+        overrides_with_all = ["force", "prompt_always", "prompt_once"],
+        help = "Prompt according to WHEN"
+    )]
+    interactive: Option<When>,
+
+    #[arg(
+        long,
+        help = "When recycling recursively, refuse directories containing other file systems"
+    )]
+    one_file_system: bool,
+
+    #[arg(
+        long,
+        overrides_with = "no_preserve_root",
+        help = "Do not recycle '/' (default)"
+    )]
+    preserve_root: bool,
+
+    #[arg(
+        long,
+        overrides_with = "preserve_root",
+        help = "Do not treat '/' specially"
+    )]
+    no_preserve_root: bool,
+
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        value_name = "DURATION",
+        conflicts_with = "pin",
+        help = "Keep the recycled files this long instead of the configured ttl, e.g. 30d"
+    )]
+    ttl: Option<u64>,
+
+    #[arg(long, help = "Keep the recycled files until they are unpinned")]
+    pin: bool,
+
+    #[arg(
+        long,
+        help = "Recycle protected paths too: the home directory, mount points, paths listed in \
+                protect and directories with a .rim-protect file"
+    )]
+    allow_protected: bool,
+
     #[arg(short, long)]
     config: Option<PathBuf>,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum When {
+    #[value(alias = "no", alias = "none")]
+    Never,
+    Once,
+    #[value(alias = "yes")]
+    Always,
+}
+
+/// How the user asked to be prompted, after resolving `-f`, `-i`, `-I` and
+/// `--interactive`; the last of these on the command line wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Prompt {
+    /// `-f`: never prompt
+    Never,
+    /// no flag: only prompt for write-protected files on a terminal
+    WriteProtected,
+    /// `-I`: prompt once for the whole invocation
+    Once,
+    /// `-i`: prompt for every operand
+    Always,
+}
+
+impl Opts {
+    fn prompt(&self) -> Prompt {
+        match self.interactive {
+            Some(When::Never) => Prompt::Never,
+            Some(When::Once) => Prompt::Once,
+            Some(When::Always) => Prompt::Always,
+            None if self.force => Prompt::Never,
+            None if self.prompt_once => Prompt::Once,
+            None if self.prompt_always => Prompt::Always,
+            None => Prompt::WriteProtected,
+        }
+    }
+}
+
+/// Subcommands built into rim. Any other first argument is a file to
+/// recycle.
+#[derive(Parser, Debug)]
+#[clap(name = "rim", version = "0.1.0", author = "Zelly Snyder")]
+struct BuiltinOpts {
+    #[command(subcommand)]
+    command: Builtin,
+
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Builtin {
+    /// List the contents of the trash
+    List(ListOpts),
+    /// Permanently delete files whose time in the trash is up
+    Maintenance(MaintenanceOpts),
+    /// Permanently delete entries from the trash now, as `purge_mode` says
+    Purge(PurgeOpts),
+    /// Keep entries in the trash until they are unpinned
+    Pin(PinOpts),
+    /// Let pinned entries expire again
+    Unpin(PinOpts),
+    /// Keep an entry in the trash for longer
+    Extend(ExtendOpts),
+    /// Print the path of the LD_PRELOAD library which sends other programs'
+    /// deletions to rim, e.g. LD_PRELOAD=$(rim preload) make clean
+    Preload,
+    /// Recycle a path for the LD_PRELOAD library, reporting back through the
+    /// exit status
+    #[command(hide = true)]
+    Intercept(InterceptOpts),
+}
+
+#[derive(Args, Debug)]
+struct InterceptOpts {
+    #[arg(long, help = "The path is an empty directory, as for rmdir(2)")]
+    dir: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "dir",
+        help = "The path is about to be overwritten; keep a copy of it"
+    )]
+    overwrite: bool,
+
+    path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct PinOpts {
+    #[arg(required = true, help = "Ids of the entries, as shown by `rim list`")]
+    ids: Vec<i64>,
+}
+
+#[derive(Args, Debug)]
+struct ExtendOpts {
+    #[arg(help = "Id of the entry, as shown by `rim list`")]
+    id: i64,
+
+    #[arg(value_parser = parse_duration, help = "How much longer to keep it, e.g. 30d")]
+    duration: u64,
+}
+
+#[derive(Args, Debug)]
+struct MaintenanceOpts {
+    #[arg(long, value_enum, default_value = "table", help = "Output format")]
+    format: Format,
+}
+
+#[derive(Args, Debug)]
+struct PurgeOpts {
+    #[arg(
+        required = true,
+        help = "Ids of the entries to purge, as shown by `rim list`"
+    )]
+    ids: Vec<i64>,
+
+    #[arg(long, value_enum, default_value = "table", help = "Output format")]
+    format: Format,
+}
+
+#[derive(Args, Debug)]
+struct ListOpts {
+    #[arg(short = 'n', long, help = "Show at most this many entries")]
+    limit: Option<u32>,
+
+    #[arg(long, value_parser = parse_time, help = "Only show files deleted at or after this time, e.g. 2024-02-28 or 2d (ago)")]
+    since: Option<u64>,
+
+    #[arg(long, value_parser = parse_time, help = "Only show files deleted at or before this time, e.g. 2024-02-28 or 2d (ago)")]
+    until: Option<u64>,
+
+    #[arg(long, help = "Only show files at or under this path")]
+    path_prefix: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Show every trashed version of this file, newest first"
+    )]
+    history: Option<PathBuf>,
+
+    #[arg(long, help = "Only show directories")]
+    dirs_only: bool,
+
+    #[arg(
+        long,
+        value_name = "ID",
+        help = "Only show files deleted by one command run under rim-wrap"
+    )]
+    operation: Option<i64>,
+
+    #[arg(long, value_enum, default_value = "date", help = "Sort order")]
+    sort: Sort,
+
+    #[arg(short, long, help = "Reverse the sort order")]
+    reverse: bool,
+
+    #[arg(long, value_enum, default_value = "table", help = "Output format")]
+    format: Format,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Sort {
+    Date,
+    Size,
+    Path,
+}
+
+impl From<Sort> for SortKey {
+    fn from(sort: Sort) -> SortKey {
+        match sort {
+            Sort::Date => SortKey::Date,
+            Sort::Size => SortKey::Size,
+            Sort::Path => SortKey::Path,
+        }
+    }
+}
+
 fn main() {
-    let opts: Opts = Opts::parse();
+    let args: Vec<String> = std::env::args().collect();
+    let invocation = split_subcommand(&args);
+    // builtins come first, whatever rim-<name> programs are around
+    let external = invocation.filter(|(_, name, _)| !Builtin::has_subcommand(name));
+    if let Some((config, name, rest)) = external {
+        if let Ok(subcommand) = name.parse::<ExternalSubcommand>() {
+            match subcommand_path(&subcommand) {
+                Some(path) => run_external(&path, config, rest),
+                None if subcommand.is_bundled() => {
+                    eprintln!(
+                        "rim: rim-{} is neither installed next to rim nor on $PATH",
+                        subcommand
+                    );
+                    eprintln!("Use 'rim -- {}' to recycle a file of that name.", name);
+                    std::process::exit(1);
+                }
+                // not a subcommand after all, but a file to recycle
+                None => (),
+            }
+        }
+    }
+    if invocation.is_some_and(|(_, name, _)| Builtin::has_subcommand(name)) {
+        let opts = BuiltinOpts::parse();
+        if let Builtin::Intercept(intercept_opts) = &opts.command {
+            intercept(opts.config.clone(), intercept_opts);
+        }
+        let config = Rc::new(Config::load(opts.config).expect("Error opening config file"));
+        let app = App::new(config).unwrap();
+        let result = match opts.command {
+            Builtin::List(list_opts) => list(&app, &list_opts),
+            Builtin::Maintenance(maintenance_opts) => maintenance(&app, &maintenance_opts),
+            Builtin::Purge(purge_opts) => purge(&app, &purge_opts),
+            Builtin::Pin(pin_opts) => pin(&app, &pin_opts, true),
+            Builtin::Unpin(pin_opts) => pin(&app, &pin_opts, false),
+            Builtin::Extend(extend_opts) => extend(&app, &extend_opts),
+            Builtin::Preload => preload::library_path().map(|path| println!("{}", path.display())),
+            Builtin::Intercept(_) => unreachable!(),
+        };
+        if let Err(e) = result {
+            eprintln!("rim: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let opts: Opts = match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            // rm(1) reports usage errors with the same status as failures
+            std::process::exit(1);
+        }
+    };
+    if opts.filenames.is_empty() {
+        if opts.force {
+            return;
+        }
+        eprintln!("rim: missing operand");
+        eprintln!("Try 'rim --help' for more information.");
+        std::process::exit(1);
+    }
+    let prompt = opts.prompt();
+    if prompt == Prompt::Once && (opts.filenames.len() > 3 || opts.recursive) {
+        let n = opts.filenames.len();
+        let question = format!(
+            "rim: remove {} argument{}{}?",
+            n,
+            if n == 1 { "" } else { "s" },
+            if opts.recursive { " recursively" } else { "" }
+        );
+        if !confirm(&question) {
+            return;
+        }
+    }
     let config = Rc::new(Config::load(opts.config.clone()).expect("Error opening config file"));
     let app = App::new(config).unwrap();
-    let mut filename = opts.filename.clone();
-    if filename.is_relative() {
-        let cwd = std::env::current_dir().expect("Can't tell what directory this is in");
-        filename = cwd.join(&filename);
+    let cwd = std::env::current_dir().expect("Can't tell what directory this is in");
+    let mut failures: Vec<(PathBuf, std::io::Error)> = vec![];
+    for operand in opts.filenames.iter() {
+        for filename in expand_operand(operand) {
+            match recycle(&app, &opts, prompt, &cwd, &filename) {
+                Ok(true) if opts.verbose => println!("removed '{}'", filename.display()),
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("rim: cannot remove '{}': {}", filename.display(), e);
+                    failures.push((filename, e));
+                }
+            }
+        }
+    }
+    if !failures.is_empty() {
+        eprintln!("rim: {} operand(s) could not be recycled:", failures.len());
+        for (filename, e) in failures.iter() {
+            eprintln!("  {}: {}", filename.display(), e);
+        }
+        std::process::exit(1);
     }
-    if filename.is_dir() {
-        app.recycle_dir(&filename).unwrap();
+}
+
+/// Splits the command line at the first argument after any leading
+/// `--config` option, which is where a subcommand name would appear.
+/// Returns the config file given, if any, that argument, and the arguments
+/// following it.
+fn split_subcommand(args: &[String]) -> Option<(Option<&str>, &str, &[String])> {
+    let mut config = None;
+    let mut i = 1;
+    loop {
+        match args.get(i)?.as_str() {
+            "-c" | "--config" => {
+                config = Some(args.get(i + 1)?.as_str());
+                i += 2;
+            }
+            arg if arg.starts_with("--config=") => {
+                config = Some(&arg["--config=".len()..]);
+                i += 1;
+            }
+            arg => return Some((config, arg, &args[i + 1..])),
+        }
+    }
+}
+
+fn list(app: &App, opts: &ListOpts) -> Result<(), std::io::Error> {
+    let cwd = std::env::current_dir()?;
+    let path_prefix = opts
+        .path_prefix
+        .as_ref()
+        .map(|prefix| cwd.join(prefix).components().collect::<PathBuf>());
+    let history = opts
+        .history
+        .as_ref()
+        .map(|path| cwd.join(path).components().collect::<PathBuf>());
+    let filter = ListFilter {
+        limit: opts.limit,
+        since: opts.since,
+        until: opts.until,
+        path_prefix: path_prefix.map(|p| p.to_string_lossy().to_string()),
+        original_path: history.map(|p| p.to_string_lossy().to_string()),
+        dirs_only: opts.dirs_only,
+        operation: opts.operation,
+        sort: opts.sort.into(),
+        reverse: opts.reverse,
+    };
+    let entries = app.list(&filter)?;
+    if opts.format != Format::Table {
+        print_entries(opts.format, "entries", &entries);
+        return Ok(());
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let id_width = entries
+        .iter()
+        .map(|e| e.id.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(2);
+    println!(
+        "{:>id_width$}  {:<9}  {:>9}  {:>9}  {:<9}  PATH",
+        "ID", "DELETED", "SIZE", "ON DISK", "EXPIRES"
+    );
+    for entry in entries.iter() {
+        let suffix = if entry.metadata.is_dir { "/" } else { "" };
+        // unlocking the key to show paths is left to rim-recover
+        let path = match rim::crypto::is_sealed(&entry.metadata.original_path) {
+            true => "(encrypted)",
+            false => &entry.metadata.original_path,
+        };
+        println!(
+            "{:>id_width$}  {:<9}  {:>9}  {:>9}  {:<9}  {}{}",
+            entry.id,
+            format_relative_time(entry.created_at, now),
+            format_size(entry.metadata.file_size),
+            format_size(entry.stored.stored_size),
+            match entry.pinned {
+                true => "pinned".to_string(),
+                false => format_relative_time(entry.expiration, now),
+            },
+            path,
+            suffix
+        );
+    }
+    Ok(())
+}
+
+fn maintenance(app: &App, opts: &MaintenanceOpts) -> Result<(), std::io::Error> {
+    let report = app.run_maintenance()?;
+    if opts.format != Format::Table {
+        print_entries(opts.format, "purged", &report.purged);
     } else {
-        app.recycle_file(&filename).unwrap();
+        for entry in report.purged.iter() {
+            println!("purged '{}'", entry.metadata.original_path);
+        }
+        println!(
+            "{} entr{} purged, {} freed, {} failed",
+            report.purged.len(),
+            if report.purged.len() == 1 { "y" } else { "ies" },
+            format_size(report.bytes_freed),
+            report.failures.len()
+        );
+    }
+    for (entry, e) in report.failures.iter() {
+        eprintln!(
+            "rim: cannot purge '{}': {}",
+            entry.metadata.original_path, e
+        );
+    }
+    for warning in report.warnings.iter() {
+        eprintln!("rim: warning: {}", warning);
+    }
+    if !report.failures.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn purge(app: &App, opts: &PurgeOpts) -> Result<(), std::io::Error> {
+    let mut purged = vec![];
+    let mut warnings: Vec<String> = vec![];
+    let mut failed = false;
+    for id in opts.ids.iter() {
+        match app.purge(*id) {
+            Ok((entry, entry_warnings)) => {
+                for warning in entry_warnings {
+                    if !warnings.contains(&warning) {
+                        warnings.push(warning);
+                    }
+                }
+                purged.push(entry);
+            }
+            Err(e) => {
+                eprintln!("rim: cannot purge {}: {}", id, e);
+                failed = true;
+            }
+        }
+    }
+    if opts.format != Format::Table {
+        print_entries(opts.format, "purged", &purged);
+    } else {
+        for entry in purged.iter() {
+            println!("purged '{}'", entry.metadata.original_path);
+        }
+    }
+    for warning in warnings.iter() {
+        eprintln!("rim: warning: {}", warning);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn pin(app: &App, opts: &PinOpts, pinned: bool) -> Result<(), std::io::Error> {
+    let mut failed = false;
+    for id in opts.ids.iter() {
+        match app.pin(*id, pinned) {
+            Ok(entry) => println!(
+                "{} '{}'",
+                if pinned { "pinned" } else { "unpinned" },
+                entry.metadata.original_path
+            ),
+            Err(e) => {
+                eprintln!(
+                    "rim: cannot {} {}: {}",
+                    if pinned { "pin" } else { "unpin" },
+                    id,
+                    e
+                );
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn extend(app: &App, opts: &ExtendOpts) -> Result<(), std::io::Error> {
+    let entry = app.extend(opts.id, opts.duration)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    println!(
+        "'{}' expires {}{}",
+        entry.metadata.original_path,
+        format_relative_time(entry.expiration, now),
+        if entry.pinned { " once unpinned" } else { "" }
+    );
+    Ok(())
+}
+
+/// Recycles a path another program tried to delete, for the LD_PRELOAD
+/// library. Exits with 0 once it's in the trash, [`preload::PASS`] to let the
+/// deletion go ahead (also when rim can't be used), or the errno the
+/// deletion should fail with. Prompts are out of the question here, and a
+/// panic lets the deletion go ahead as well.
+fn intercept(config: Option<PathBuf>, opts: &InterceptOpts) -> ! {
+    let status = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        intercept_status(config, opts)
+    }));
+    std::process::exit(status.unwrap_or(preload::PASS))
+}
+
+fn intercept_status(config: Option<PathBuf>, opts: &InterceptOpts) -> i32 {
+    let app = match Config::load(config).map(Rc::new) {
+        Ok(config) => match App::new(config) {
+            Ok(app) => app,
+            Err(_) => return preload::PASS,
+        },
+        Err(_) => return preload::PASS,
+    };
+    if !opts.path.is_absolute() || app.needs_passphrase() {
+        return preload::PASS;
+    }
+    // what gets deleted is the last component, wherever its parent really is
+    let path = match rim::fs::resolve_parent(&opts.path) {
+        Ok(path) if !preload::excluded(&app.config, &path) => path,
+        _ => return preload::PASS,
+    };
+    // set by rim-wrap, and passed on by the library
+    let options = RecycleOptions {
+        operation: std::env::var("RIM_OPERATION")
+            .ok()
+            .and_then(|id| id.parse().ok()),
+        ..Default::default()
+    };
+    let recycled = match path.symlink_metadata() {
+        // only a regular file has contents to lose
+        Ok(m) if opts.overwrite && (!m.is_file() || m.len() == 0) => Ok(()),
+        Ok(_) if opts.overwrite => app.snapshot_file(&path, &options),
+        Ok(m) if opts.dir && !m.is_dir() => Err(std::io::Error::from_raw_os_error(libc::ENOTDIR)),
+        Ok(m) if !opts.dir && m.is_dir() => Err(std::io::Error::from_raw_os_error(libc::EISDIR)),
+        Ok(_) if opts.dir => match std::fs::read_dir(&path).map(|mut d| d.next()) {
+            Ok(Some(_)) => Err(std::io::Error::from_raw_os_error(libc::ENOTEMPTY)),
+            Ok(None) => app.recycle_dir_with(&path, &options),
+            Err(e) => Err(e),
+        },
+        Ok(_) => app.recycle_file_with(&path, &options),
+        Err(e) if opts.overwrite && e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    match recycled {
+        Ok(()) => 0,
+        Err(e) => preload::exit_status(&e),
     }
 }
 
+/// The path an operand names, relative to `cwd`. A trailing `/` is dropped
+/// so that, as with rm(1), `link/` means the symbolic link rather than the
+/// directory it leads to.
+fn operand_path(cwd: &Path, filename: &Path) -> PathBuf {
+    cwd.join(filename).components().collect()
+}
+
+/// Applies rm(1) semantics to a single operand and recycles it. Returns
+/// `Ok(false)` when the operand was skipped without being an error, e.g. a
+/// missing file under `-f` or a declined prompt.
+fn recycle(
+    app: &App,
+    opts: &Opts,
+    prompt: Prompt,
+    cwd: &Path,
+    filename: &Path,
+) -> Result<bool, std::io::Error> {
+    if matches!(
+        filename.components().next_back(),
+        Some(std::path::Component::CurDir | std::path::Component::ParentDir)
+    ) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "refusing to remove '.' or '..' directory",
+        ));
+    }
+    let path = operand_path(cwd, filename);
+    let recycle_options = RecycleOptions {
+        ttl: opts.ttl,
+        pin: opts.pin,
+        operation: None,
+        // '/' is protected too, but rm(1) has its own way to allow it
+        allow_protected: opts.allow_protected
+            || (opts.no_preserve_root && path.canonicalize().is_ok_and(|p| p == Path::new("/"))),
+    };
+    let metadata = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && opts.force => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let recycled = if metadata.is_dir() {
+        if !opts.recursive {
+            if !opts.empty_dirs {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::IsADirectory,
+                    "Is a directory",
+                ));
+            }
+            if std::fs::read_dir(&path)?.next().is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::DirectoryNotEmpty,
+                    "Directory not empty",
+                ));
+            }
+        }
+        if opts.recursive && !opts.no_preserve_root && path.canonicalize()? == Path::new("/") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "it is dangerous to operate recursively on '/' (use --no-preserve-root to override this failsafe)",
+            ));
+        }
+        if opts.recursive && opts.one_file_system {
+            if let Some(mount) = find_other_device(&path, metadata.dev())? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!(
+                        "skipping '{}', since it's on a different device",
+                        mount.display()
+                    ),
+                ));
+            }
+        }
+        if prompt == Prompt::Always
+            && !confirm(&format!("rim: remove directory '{}'?", filename.display()))
+        {
+            return Ok(false);
+        }
+        app.recycle_dir_with(&path, &recycle_options)
+    } else {
+        let ask = match prompt {
+            Prompt::Always => true,
+            Prompt::WriteProtected => std::io::stdin().is_terminal() && is_write_protected(&path),
+            _ => false,
+        };
+        if ask {
+            let protected = if is_write_protected(&path) {
+                "write-protected "
+            } else {
+                ""
+            };
+            let kind = if metadata.is_symlink() {
+                "symbolic link"
+            } else if metadata.len() == 0 {
+                "regular empty file"
+            } else {
+                "regular file"
+            };
+            let question = format!(
+                "rim: remove {}{} '{}'?",
+                protected,
+                kind,
+                filename.display()
+            );
+            if !confirm(&question) {
+                return Ok(false);
+            }
+        }
+        app.recycle_file_with(&path, &recycle_options)
+    };
+    let recycled = recycled.map_err(|e| {
+        match e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<protect::Protected>())
+        {
+            Some(protected) => std::io::Error::new(
+                e.kind(),
+                format!(
+                    "{} (use --allow-protected to override this failsafe)",
+                    protected.reason
+                ),
+            ),
+            None => e,
+        }
+    });
+    delete_if_full(app, prompt, recycled, &path, filename)
+}
+
+/// Falls back to deleting a file permanently, after asking, when it doesn't
+/// fit in the trash and `when_full` allows it. Without a terminal to ask
+/// on, or under `-f`, the file stays where it is.
+fn delete_if_full(
+    app: &App,
+    prompt: Prompt,
+    recycled: Result<(), std::io::Error>,
+    path: &Path,
+    filename: &Path,
+) -> Result<bool, std::io::Error> {
+    match recycled {
+        Ok(()) => Ok(true),
+        Err(e)
+            if e.kind() == std::io::ErrorKind::StorageFull
+                && app.config.when_full == WhenFull::Delete
+                && prompt != Prompt::Never
+                && std::io::stdin().is_terminal() =>
+        {
+            let question = format!("rim: {}; delete '{}' permanently?", e, filename.display());
+            if !confirm(&question) {
+                return Ok(false);
+            }
+            purge::remove(path)?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns true if the current user could not write to `path`. Symbolic
+/// links are never considered write-protected, as in rm(1).
+fn is_write_protected(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    if path.is_symlink() {
+        return false;
+    }
+    let c_path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) != 0 }
+}
+
+/// Asks a yes/no question on stderr and reads the answer from stdin.
+fn confirm(question: &str) -> bool {
+    eprint!("{} ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim_start().chars().next(), Some('y' | 'Y'))
+}
+
+/// Expands a glob pattern which the shell passed through unexpanded
+/// (e.g. because it was quoted). Operands which exist as-is, contain no
+/// wildcards or match nothing are returned unchanged so that the caller
+/// reports them like any other operand.
+fn expand_operand(operand: &std::path::Path) -> Vec<PathBuf> {
+    let pattern = operand.to_string_lossy();
+    if operand.symlink_metadata().is_ok() || !pattern.contains(['*', '?', '[']) {
+        return vec![operand.to_path_buf()];
+    }
+    let matches: Vec<PathBuf> = match glob::glob(&pattern) {
+        Ok(paths) => paths.filter_map(Result::ok).collect(),
+        Err(_) => vec![],
+    };
+    if matches.is_empty() {
+        vec![operand.to_path_buf()]
+    } else {
+        matches
+    }
+}
+
+/// Subcommands run as separate `rim-<name>` programs
 enum ExternalSubcommand {
     Recover,
     Wrap,
+    /// Anything else found as `rim-<name>`, e.g. a site's own tools
+    Custom(String),
+}
+
+impl ExternalSubcommand {
+    /// Whether it ships with rim, rather than maybe being a file name
+    fn is_bundled(&self) -> bool {
+        !matches!(self, ExternalSubcommand::Custom(_))
+    }
 }
 
-impl std::string::ToString for ExternalSubcommand {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ExternalSubcommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use ExternalSubcommand::*;
         match self {
-            Recover => "recover".to_string(),
-            Wrap => "wrap".to_string(),
+            Recover => write!(f, "recover"),
+            Wrap => write!(f, "wrap"),
+            Custom(name) => write!(f, "{}", name),
         }
     }
 }
@@ -70,16 +888,125 @@ impl std::str::FromStr for ExternalSubcommand {
         match s {
             "recover" => Ok(Recover),
             "wrap" => Ok(Wrap),
-            _ => Err(format!("Unknown subcommand: {}", s)),
+            _ if s.is_empty() || s.starts_with('-') || s.contains('/') => {
+                Err(format!("Not a subcommand name: {}", s))
+            }
+            _ if Builtin::has_subcommand(s) => Err(format!("Not an external subcommand: {}", s)),
+            _ => Ok(Custom(s.to_string())),
         }
     }
 }
 
-/// Find the path to a related CLI binary which has the prefix "rim-".
-fn subcommand_path(subcommand: &ExternalSubcommand) -> Result<PathBuf, std::io::Error> {
-    let mut here = std::env::current_exe()?;
-    here.pop();
-    let p = "rim-".to_string() + &subcommand.to_string();
-    here.push(p);
-    Ok(here)
+/// Find the path to a related CLI binary which has the prefix "rim-": the
+/// one installed next to rim, or else the first on `$PATH`.
+fn subcommand_path(subcommand: &ExternalSubcommand) -> Option<PathBuf> {
+    let name = format!("rim-{}", subcommand);
+    let here = std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.to_path_buf()));
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    here.into_iter()
+        .chain(std::env::split_paths(&path))
+        .map(|dir| dir.join(&name))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.mode() & 0o111 != 0)
+        })
+}
+
+/// Replaces rim with the subcommand at `path`, passing on `args` and the
+/// config file as `RIM_CONFIG`.
+fn run_external(path: &Path, config: Option<&str>, args: &[String]) -> ! {
+    use std::os::unix::process::CommandExt;
+    let mut command = std::process::Command::new(path);
+    command.args(args);
+    if let Some(config) = config {
+        command.env("RIM_CONFIG", config);
+    }
+    let e = command.exec();
+    eprintln!("rim: {}: {}", path.display(), e);
+    std::process::exit(126);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_subcommand() {
+        let full = args(&["rim", "-c", "rim.yaml", "list", "-n", "3"]);
+        let (config, name, rest) = split_subcommand(&full).unwrap();
+        assert_eq!((config, name), (Some("rim.yaml"), "list"));
+        assert_eq!(rest, ["-n", "3"]);
+        let full = args(&["rim", "--config=rim.yaml", "recover", "4"]);
+        assert_eq!(
+            split_subcommand(&full),
+            Some((Some("rim.yaml"), "recover", &full[3..]))
+        );
+        assert_eq!(split_subcommand(&args(&["rim"])), None);
+        assert_eq!(split_subcommand(&args(&["rim", "--config"])), None);
+
+        // `rim -- recover` recycles a file named recover
+        let full = args(&["rim", "--", "recover"]);
+        let (_, name, rest) = split_subcommand(&full).unwrap();
+        assert_eq!((name, rest), ("--", &full[2..]));
+        assert!(name.parse::<ExternalSubcommand>().is_err());
+        assert!(!Builtin::has_subcommand(name));
+    }
+
+    #[test]
+    fn test_operand_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("target")).unwrap();
+        std::os::unix::fs::symlink("target", dir.path().join("link")).unwrap();
+        let path = operand_path(dir.path(), Path::new("link/"));
+        assert_eq!(path, dir.path().join("link"));
+        assert!(path.symlink_metadata().unwrap().is_symlink());
+        assert_eq!(
+            operand_path(Path::new("/home/me"), Path::new("/")),
+            Path::new("/")
+        );
+    }
+
+    #[test]
+    fn test_delete_if_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            trashdir: dir.path().join("trash"),
+            when_full: WhenFull::Delete,
+            ..Default::default()
+        };
+        std::fs::create_dir(&config.trashdir).unwrap();
+        let app = App::new(Rc::new(config)).unwrap();
+        let file = dir.path().join("big");
+        std::fs::write(&file, "big").unwrap();
+        // -f never stops to ask, so the file can't be deleted for good
+        let full = std::io::Error::new(std::io::ErrorKind::StorageFull, "The trash is full");
+        let e = delete_if_full(&app, Prompt::Never, Err(full), &file, &file).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn test_external_subcommand() {
+        assert!("recover"
+            .parse::<ExternalSubcommand>()
+            .is_ok_and(|s| s.is_bundled()));
+        assert!("audit"
+            .parse::<ExternalSubcommand>()
+            .is_ok_and(|s| !s.is_bundled()));
+        for name in ["", "-f", "./recover", "a/b"] {
+            assert!(name.parse::<ExternalSubcommand>().is_err());
+        }
+        // a rim-list on $PATH can't take over the builtin
+        for name in ["list", "purge", "intercept"] {
+            assert!(Builtin::has_subcommand(name));
+            assert!(name.parse::<ExternalSubcommand>().is_err());
+        }
+    }
 }
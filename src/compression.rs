@@ -0,0 +1,155 @@
+//! Optional compression of trashed files and directory archives
+
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Read, Write};
+
+/// How a payload is encoded on disk. The codec is recorded with every trash
+/// entry, so changing the configuration never breaks recovery of files
+/// trashed before.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    /// Suffix of the objects stored with this codec, e.g. `.zst`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Zstd => ".zst",
+            Codec::Gzip => ".gz",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codec, String> {
+        match s {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            _ => Err(format!("unknown compression codec '{}'", s)),
+        }
+    }
+}
+
+/// The `compression` setting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    #[serde(default)]
+    pub codec: Codec,
+    /// 1 to 22 for zstd (default 3), 0 to 9 for gzip (default 6)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+/// A writer which compresses whatever goes through it with the configured
+/// codec. [`Encoder::finish`] must be called to write the trailer.
+pub enum Encoder<W: Write> {
+    Plain(W),
+    Zstd(zstd::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(compression: Compression, inner: W) -> Result<Encoder<W>, std::io::Error> {
+        Ok(match compression.codec {
+            Codec::None => Encoder::Plain(inner),
+            Codec::Zstd => {
+                Encoder::Zstd(zstd::Encoder::new(inner, compression.level.unwrap_or(3))?)
+            }
+            Codec::Gzip => {
+                let level = compression.level.unwrap_or(6).clamp(0, 9) as u32;
+                Encoder::Gzip(flate2::write::GzEncoder::new(
+                    inner,
+                    flate2::Compression::new(level),
+                ))
+            }
+        })
+    }
+
+    /// Flushes the compressed stream and hands back the inner writer.
+    pub fn finish(self) -> Result<W, std::io::Error> {
+        match self {
+            Encoder::Plain(w) => Ok(w),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Gzip(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Plain(w) => w.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Plain(w) => w.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Gzip(e) => e.flush(),
+        }
+    }
+}
+
+/// Wraps `reader` so that reading from it yields the decompressed data.
+pub fn decoder<'a, R: BufRead + 'a>(
+    codec: Codec,
+    reader: R,
+) -> Result<Box<dyn Read + 'a>, std::io::Error> {
+    Ok(match codec {
+        Codec::None => Box::new(reader),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = "all work and no play makes jack a dull boy\n".repeat(100);
+        for codec in [Codec::None, Codec::Zstd, Codec::Gzip] {
+            let compression = Compression { codec, level: None };
+            let mut encoder = Encoder::new(compression, Vec::new()).unwrap();
+            encoder.write_all(data.as_bytes()).unwrap();
+            let encoded = encoder.finish().unwrap();
+            if codec != Codec::None {
+                assert!(encoded.len() < data.len() / 10);
+            }
+            let mut decoded = String::new();
+            decoder(codec, encoded.as_slice())
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data);
+            assert_eq!(codec.as_str().parse::<Codec>(), Ok(codec));
+        }
+    }
+}
@@ -1,15 +1,199 @@
 //! Configuration file format
 
+use crate::compression::Compression;
+use crate::purge::{Pattern, PurgeMode};
+use crate::rules::Rule;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const DEFAULT_DATABASE_NAME: &str = "rim.db";
 
+/// Where trashed payloads are kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    /// Under `trashdir`, with directories packed into tarballs
+    #[default]
+    Rim,
+    /// In the FreeDesktop.org trash shared with desktop file managers
+    Freedesktop,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub trashdir: PathBuf,
     pub database_name: String,
+    /// Seconds to keep trashed files, or a duration like `7d`
+    #[serde(deserialize_with = "crate::human::de::duration")]
     pub ttl: u64,
+    /// Keep files deleted from other file systems in a `.rim-trash-$UID`
+    /// directory at the top of their mount point instead of copying them
+    /// into `trashdir`. The database in `trashdir` still indexes them.
+    #[serde(default)]
+    pub per_mount_trash: bool,
+    #[serde(default)]
+    pub storage: Storage,
+    /// The FreeDesktop.org home trash; defaults to `$XDG_DATA_HOME/Trash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freedesktop_trashdir: Option<PathBuf>,
+    /// Compress files and directory archives kept under `trashdir`. Payloads
+    /// in the FreeDesktop.org trash are never compressed.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Encrypt payloads kept under `trashdir`; off unless present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// How payloads are destroyed when they expire or are purged
+    #[serde(default)]
+    pub purge_mode: PurgeMode,
+    /// Overwrite passes; 1 for `overwrite` and 3 for `shred` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_passes: Option<u32>,
+    /// Zeros for `overwrite` and random data for `shred` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_pattern: Option<Pattern>,
+    /// Evict entries once the payloads in the trash take up more than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_trash_size: Option<Limit>,
+    /// Evict entries while the file system holding the trash has less free
+    /// space than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_space: Option<Limit>,
+    /// Which entries are evicted first to stay within the limits above
+    #[serde(default)]
+    pub eviction: Eviction,
+    /// What happens to a file which can't fit in the trash at all
+    #[serde(default)]
+    pub when_full: WhenFull,
+    /// Per-path exceptions to `ttl` and `compression`, in order; the first
+    /// rule which applies to a file wins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// Paths which are never recycled, nor any directory containing them,
+    /// on top of the root and home directories, mount points and
+    /// directories with a `.rim-protect` file. A leading `~` stands for the
+    /// home directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protect: Vec<PathBuf>,
+    /// Directories whose files the LD_PRELOAD library lets other programs
+    /// delete for real; temporary files come and go all the time.
+    #[serde(default = "default_preload_exclude")]
+    pub preload_exclude: Vec<PathBuf>,
+    /// Whether rim-wrap has the LD_PRELOAD library also keep a copy of files
+    /// which are about to be replaced by a rename or truncated on open, not
+    /// just deleted ones
+    #[serde(default)]
+    pub preload_overwrites: bool,
+}
+
+/// Replaces a leading `~` with the home directory, if there is one.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs_next::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn default_preload_exclude() -> Vec<PathBuf> {
+    ["/tmp", "/var/tmp", "/dev/shm"]
+        .iter()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// A size limit: a number of bytes, possibly written like `500MiB`, or a
+/// percentage of the file system the trash is on, written like `10%`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RawLimit")]
+pub enum Limit {
+    Bytes(u64),
+    Percent(f64),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLimit {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Limit {
+    /// The limit in bytes, on a file system of `disk_size` bytes
+    pub fn bytes(&self, disk_size: u64) -> u64 {
+        match self {
+            Limit::Bytes(n) => *n,
+            Limit::Percent(p) => (disk_size as f64 * p / 100.0) as u64,
+        }
+    }
+}
+
+impl std::str::FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Limit, String> {
+        match s.trim().strip_suffix('%') {
+            Some(p) => match p.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Limit::Percent(p)),
+                _ => Err(format!("invalid percentage '{}'", s)),
+            },
+            None => crate::human::parse_size(s).map(Limit::Bytes),
+        }
+    }
+}
+
+impl TryFrom<RawLimit> for Limit {
+    type Error = String;
+
+    fn try_from(raw: RawLimit) -> Result<Limit, String> {
+        match raw {
+            RawLimit::Bytes(n) => Ok(Limit::Bytes(n)),
+            RawLimit::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl Serialize for Limit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Limit::Bytes(n) => serializer.serialize_u64(*n),
+            Limit::Percent(p) => serializer.serialize_str(&format!("{}%", p)),
+        }
+    }
+}
+
+/// The `eviction` setting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// Entries deleted longest ago go first
+    #[default]
+    Oldest,
+    /// The biggest entries go first
+    Largest,
+}
+
+/// The `when_full` setting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// Leave the file alone and report an error
+    #[default]
+    Refuse,
+    /// Offer to delete the file permanently instead
+    Delete,
+}
+
+/// The `encryption` setting
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Derive the key from this file instead of asking for a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Also encrypt the original path, hash and link target of every entry
+    /// in the database. Such entries can only be listed by path or found by
+    /// hash once the key is unlocked.
+    #[serde(default)]
+    pub encrypt_metadata: bool,
 }
 
 impl Default for Config {
@@ -20,6 +204,22 @@ impl Default for Config {
             trashdir,
             database_name: DEFAULT_DATABASE_NAME.to_string(),
             ttl: 604800,
+            per_mount_trash: false,
+            storage: Storage::Rim,
+            freedesktop_trashdir: None,
+            compression: Compression::default(),
+            encryption: None,
+            purge_mode: PurgeMode::Unlink,
+            purge_passes: None,
+            purge_pattern: None,
+            max_trash_size: None,
+            min_free_space: None,
+            eviction: Eviction::Oldest,
+            when_full: WhenFull::Refuse,
+            rules: vec![],
+            protect: vec![],
+            preload_exclude: default_preload_exclude(),
+            preload_overwrites: false,
         }
     }
 }
@@ -32,16 +232,26 @@ impl Config {
 
     pub fn open(path: &std::path::Path) -> Result<Config, std::io::Error> {
         let s = std::fs::read_to_string(path)?;
-        let config: Config = match serde_yaml::from_str(&s) {
+        let mut config: Config = match serde_yaml::from_str(&s) {
             Ok(c) => c,
             Err(e) => {
-                println!("Error parsing config file: {}", e);
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
-                    "Error parsing config file",
+                    format!("Error parsing config file: {}", e),
                 ));
             }
         };
+        config.trashdir = expand_home(&config.trashdir);
+        config.freedesktop_trashdir = config.freedesktop_trashdir.as_deref().map(expand_home);
+        if let Some(encryption) = config.encryption.as_mut() {
+            encryption.key_file = encryption.key_file.as_deref().map(expand_home);
+        }
+        config.protect = config.protect.iter().map(|p| expand_home(p)).collect();
+        config.preload_exclude = config
+            .preload_exclude
+            .iter()
+            .map(|p| expand_home(p))
+            .collect();
         Ok(config)
     }
 
@@ -49,50 +259,50 @@ impl Config {
         self.trashdir.join(&self.database_name)
     }
 
+    /// Where the public key payloads are encrypted to is kept
+    pub fn keyring_path(&self) -> PathBuf {
+        self.trashdir.join("keyring")
+    }
+
+    pub fn freedesktop_trashdir(&self) -> Option<PathBuf> {
+        self.freedesktop_trashdir
+            .clone()
+            .or_else(crate::freedesktop::home_trash)
+    }
+
     pub fn load(config_file: Option<PathBuf>) -> Result<Config, std::io::Error> {
-        match config_file {
+        match config_file.or_else(Config::find) {
             Some(path) => Config::open(&path),
             None => {
-                let mut config = Config::default();
-                let mut config_paths: Vec<PathBuf> = vec![];
-                if let Ok(xdg_config_home) = std::env::var("XDG_CONFIG_HOME") {
-                    config_paths.push(
-                        PathBuf::from(xdg_config_home)
-                            .join("rim")
-                            .join("config.yaml"),
-                    );
-                }
-                config_paths.push(
-                    dirs_next::home_dir()
-                        .unwrap()
-                        .join(".config")
-                        .join("rim")
-                        .join("config.yaml"),
-                );
-                config_paths.push(
-                    dirs_next::home_dir()
-                        .unwrap()
-                        .join(".rim")
-                        .join("config.yaml"),
-                );
-                config_paths.push(dirs_next::home_dir().unwrap().join(".rim.yaml"));
-                let mut any_found: bool = false;
-                for path in config_paths.iter() {
-                    if path.exists() {
-                        config = Config::open(path).expect("Error opening config file");
-                        any_found = true;
-                        break;
-                    }
-                }
-                if !any_found {
-                    let destination = Self::create()?;
-                    config.save(&destination)?;
-                }
+                let config = Config::default();
+                let destination = Self::create()?;
+                config.save(&destination)?;
                 Ok(config)
             }
         }
     }
 
+    /// The config file `load` reads when none is given, if there is one:
+    /// `$RIM_CONFIG`, or the first of the usual places which exists
+    pub fn find() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("RIM_CONFIG").filter(|p| !p.is_empty()) {
+            return Some(PathBuf::from(path));
+        }
+        let mut config_paths: Vec<PathBuf> = vec![];
+        if let Ok(xdg_config_home) = std::env::var("XDG_CONFIG_HOME") {
+            config_paths.push(
+                PathBuf::from(xdg_config_home)
+                    .join("rim")
+                    .join("config.yaml"),
+            );
+        }
+        let home = dirs_next::home_dir().unwrap();
+        config_paths.push(home.join(".config").join("rim").join("config.yaml"));
+        config_paths.push(home.join(".rim").join("config.yaml"));
+        config_paths.push(home.join(".rim.yaml"));
+        config_paths.into_iter().find(|path| path.exists())
+    }
+
     /// Creates config directory, returning the path to the config
     /// file that should be written to.
     fn create() -> Result<PathBuf, std::io::Error> {
@@ -111,3 +321,58 @@ impl Config {
         Ok(destination)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let config: Config = serde_yaml::from_str(
+            "trashdir: /tmp/rim\ndatabase_name: rim.db\nttl: 60\nmax_trash_size: 1000\nmin_free_space: 10%\n",
+        )
+        .unwrap();
+        assert_eq!(config.ttl, 60);
+        assert_eq!(config.max_trash_size, Some(Limit::Bytes(1000)));
+        assert_eq!(config.min_free_space, Some(Limit::Percent(10.0)));
+        assert_eq!(Limit::Percent(10.0).bytes(5000), 500);
+        assert!("110%".parse::<Limit>().is_err());
+        assert!("lots".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rim.yaml");
+        std::fs::write(
+            &path,
+            "trashdir: ~/.rim-trash\ndatabase_name: rim.db\nttl: 60\nencryption:\n  key_file: ~/.rim/key\n",
+        )
+        .unwrap();
+        let config = Config::open(&path).unwrap();
+        let home = dirs_next::home_dir().unwrap();
+        assert_eq!(config.trashdir, home.join(".rim-trash"));
+        assert_eq!(
+            config.encryption.unwrap().key_file,
+            Some(home.join(".rim/key"))
+        );
+        assert_eq!(expand_home(Path::new("/~x")), Path::new("/~x"));
+        assert_eq!(expand_home(Path::new("~user/x")), Path::new("~user/x"));
+    }
+
+    #[test]
+    fn test_human_values() {
+        let config: Config = serde_yaml::from_str(
+            "trashdir: /tmp/rim\ndatabase_name: rim.db\nttl: 2w\nmax_trash_size: 500MiB\nrules:\n  - min_size: 1G\n    ttl: 36h\n",
+        )
+        .unwrap();
+        assert_eq!(config.ttl, 14 * 86400);
+        assert_eq!(config.max_trash_size, Some(Limit::Bytes(500 << 20)));
+        assert_eq!(config.rules[0].min_size, Some(1 << 30));
+        assert_eq!(config.rules[0].ttl, Some(36 * 3600));
+        assert!(serde_yaml::from_str::<Config>(
+            "trashdir: /tmp/rim\ndatabase_name: rim.db\nttl: a while\n"
+        )
+        .is_err());
+    }
+}
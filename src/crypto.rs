@@ -0,0 +1,434 @@
+//! Opt-in encryption at rest of trashed payloads.
+//!
+//! Payloads are sealed to an X25519 public key, so deleting files only needs
+//! the public half, which is kept in a `keyring` file in the trash directory.
+//! Recovering them needs the secret half, derived with Argon2id from a
+//! passphrase or a key file. Every payload gets an ephemeral key pair whose
+//! shared secret keys an XChaCha20-Poly1305 STREAM over 64 KiB chunks, which
+//! detects truncation and reordering as well as tampering.
+//!
+//! A sealed payload starts with `rimenc1\n`, the ephemeral public key and
+//! the stream nonce, followed by the chunks.
+
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::XChaCha20Poly1305;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"rimenc1\n";
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+const STREAM_NONCE: usize = 19;
+const NONCE: usize = 24;
+const SEALED_PREFIX: &str = "sealed:";
+
+#[derive(Clone)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+pub struct SecretKey(x25519_dalek::StaticSecret);
+
+impl SecretKey {
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0))
+    }
+}
+
+/// The `keyring` file: the salt the secret key is derived with, and the
+/// public key it yields.
+pub struct Keyring {
+    salt: [u8; 16],
+    public: PublicKey,
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> Result<Option<Keyring>, std::io::Error> {
+        let s = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut salt = None;
+        let mut public = None;
+        for line in s.lines() {
+            match line.split_once(' ') {
+                Some(("salt", value)) => salt = hex_decode(value).and_then(|v| v.try_into().ok()),
+                Some(("public", value)) => {
+                    public = hex_decode(value).and_then(|v| <[u8; 32]>::try_from(v).ok())
+                }
+                _ => (),
+            }
+        }
+        match (salt, public) {
+            (Some(salt), Some(public)) => Ok(Some(Keyring {
+                salt,
+                public: PublicKey(public.into()),
+            })),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Malformed keyring {}", path.display()),
+            )),
+        }
+    }
+
+    /// Sets up encryption for a trash directory, deriving a new key from
+    /// `material`. Fails if a keyring already exists.
+    pub fn create(path: &Path, material: &[u8]) -> Result<(Keyring, SecretKey), std::io::Error> {
+        let mut salt = [0u8; 16];
+        rand_bytes(&mut salt);
+        let secret = derive(material, &salt)?;
+        let keyring = Keyring {
+            salt,
+            public: secret.public_key(),
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        writeln!(file, "salt {}", hex_encode(&keyring.salt))?;
+        writeln!(file, "public {}", hex_encode(keyring.public.0.as_bytes()))?;
+        file.sync_all()?;
+        Ok((keyring, secret))
+    }
+
+    /// Derives the secret key from `material`, checking it against the
+    /// public key on record.
+    pub fn unlock(&self, material: &[u8]) -> Result<SecretKey, std::io::Error> {
+        let secret = derive(material, &self.salt)?;
+        if secret.public_key().0 != self.public.0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Wrong passphrase or key file",
+            ));
+        }
+        Ok(secret)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+}
+
+/// Reads what the key is derived from: the key file if one is configured,
+/// otherwise `$RIM_PASSPHRASE`, otherwise a passphrase typed at the
+/// terminal, twice when `confirm` is set.
+pub fn key_material(key_file: Option<&Path>, confirm: bool) -> Result<Vec<u8>, std::io::Error> {
+    if let Some(key_file) = key_file {
+        return std::fs::read(key_file);
+    }
+    if let Ok(passphrase) = std::env::var("RIM_PASSPHRASE") {
+        return Ok(passphrase.into_bytes());
+    }
+    let passphrase = rpassword::prompt_password("rim: passphrase: ")?;
+    if confirm && rpassword::prompt_password("rim: repeat passphrase: ")? != passphrase {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Passphrases don't match",
+        ));
+    }
+    Ok(passphrase.into_bytes())
+}
+
+fn derive(material: &[u8], salt: &[u8]) -> Result<SecretKey, std::io::Error> {
+    let mut bytes = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(material, salt, &mut bytes)
+        .map_err(|e| std::io::Error::other(format!("Key derivation failed: {}", e)))?;
+    Ok(SecretKey(bytes.into()))
+}
+
+/// Agrees on a key for one payload between an ephemeral key pair and the
+/// recipient's key.
+fn payload_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("rim 2024-03-01 payload key");
+    hasher.update(shared);
+    hasher.update(ephemeral);
+    hasher.update(recipient);
+    *hasher.finalize().as_bytes()
+}
+
+/// Starts a payload for `recipient`: returns the cipher keyed for it along
+/// with the ephemeral public key, which has to be stored with the payload.
+fn ephemeral_cipher(recipient: &PublicKey) -> (XChaCha20Poly1305, [u8; 32]) {
+    let ephemeral = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&recipient.0);
+    let key = payload_key(shared.as_bytes(), &ephemeral_public, recipient.0.as_bytes());
+    (XChaCha20Poly1305::new(&key.into()), ephemeral_public)
+}
+
+fn recipient_cipher(secret: &SecretKey, ephemeral_public: [u8; 32]) -> XChaCha20Poly1305 {
+    let shared = secret.0.diffie_hellman(&ephemeral_public.into());
+    let key = payload_key(
+        shared.as_bytes(),
+        &ephemeral_public,
+        secret.public_key().0.as_bytes(),
+    );
+    XChaCha20Poly1305::new(&key.into())
+}
+
+fn corrupt() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Encrypted payload is damaged or was sealed with another key",
+    )
+}
+
+/// A writer which encrypts whatever goes through it to `recipient`, or
+/// passes it through unchanged without one. [`Writer::finish`] must be
+/// called to seal the last chunk.
+pub enum Writer<W: Write> {
+    Plain(W),
+    Sealed(Box<SealedWriter<W>>),
+}
+
+pub struct SealedWriter<W: Write> {
+    inner: W,
+    stream: EncryptorBE32<XChaCha20Poly1305>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(recipient: Option<&PublicKey>, mut inner: W) -> Result<Writer<W>, std::io::Error> {
+        let recipient = match recipient {
+            Some(r) => r,
+            None => return Ok(Writer::Plain(inner)),
+        };
+        let (cipher, ephemeral_public) = ephemeral_cipher(recipient);
+        let mut nonce = [0u8; STREAM_NONCE];
+        rand_bytes(&mut nonce);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&ephemeral_public)?;
+        inner.write_all(&nonce)?;
+        Ok(Writer::Sealed(Box::new(SealedWriter {
+            inner,
+            stream: EncryptorBE32::from_aead(cipher, (&nonce).into()),
+            buffer: Vec::with_capacity(CHUNK),
+        })))
+    }
+
+    /// Seals the last chunk and hands back the inner writer.
+    pub fn finish(self) -> Result<W, std::io::Error> {
+        match self {
+            Writer::Plain(w) => Ok(w),
+            Writer::Sealed(sealed) => {
+                let SealedWriter {
+                    mut inner,
+                    stream,
+                    buffer,
+                } = *sealed;
+                let chunk = stream
+                    .encrypt_last(buffer.as_slice())
+                    .map_err(|_| std::io::Error::other("Encryption failed"))?;
+                inner.write_all(&chunk)?;
+                Ok(inner)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let sealed = match self {
+            Writer::Plain(w) => return w.write(buf),
+            Writer::Sealed(sealed) => sealed,
+        };
+        let n = buf.len().min(CHUNK - sealed.buffer.len());
+        sealed.buffer.extend_from_slice(&buf[..n]);
+        // Full chunks go out right away, so the last chunk is always a
+        // short one and readers can tell it apart by its length.
+        if sealed.buffer.len() == CHUNK {
+            let chunk = sealed
+                .stream
+                .encrypt_next(sealed.buffer.as_slice())
+                .map_err(|_| std::io::Error::other("Encryption failed"))?;
+            sealed.inner.write_all(&chunk)?;
+            sealed.buffer.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Writer::Plain(w) => w.flush(),
+            Writer::Sealed(sealed) => sealed.inner.flush(),
+        }
+    }
+}
+
+/// Decrypts a payload written by [`Writer`]
+struct Reader<R: Read> {
+    inner: R,
+    stream: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.stream.is_none() {
+                return Ok(0);
+            }
+            let mut chunk = vec![0u8; CHUNK + TAG];
+            let mut len = 0;
+            while len < chunk.len() {
+                match self.inner.read(&mut chunk[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            chunk.truncate(len);
+            self.plain = if len == CHUNK + TAG {
+                let stream = self.stream.as_mut().unwrap();
+                stream
+                    .decrypt_next(chunk.as_slice())
+                    .map_err(|_| corrupt())?
+            } else {
+                let stream = self.stream.take().unwrap();
+                stream
+                    .decrypt_last(chunk.as_slice())
+                    .map_err(|_| corrupt())?
+            };
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Wraps `inner` so that reading from it yields the decrypted payload, or
+/// the payload as-is without a key.
+pub fn reader<'a, R: Read + 'a>(
+    key: Option<&SecretKey>,
+    mut inner: R,
+) -> Result<Box<dyn Read + 'a>, std::io::Error> {
+    let key = match key {
+        Some(k) => k,
+        None => return Ok(Box::new(inner)),
+    };
+    let mut header = [0u8; 8 + 32 + STREAM_NONCE];
+    inner.read_exact(&mut header).map_err(|_| corrupt())?;
+    if &header[..8] != MAGIC {
+        return Err(corrupt());
+    }
+    let ephemeral_public: [u8; 32] = header[8..40].try_into().unwrap();
+    let nonce: [u8; STREAM_NONCE] = header[40..].try_into().unwrap();
+    let cipher = recipient_cipher(key, ephemeral_public);
+    Ok(Box::new(Reader {
+        inner,
+        stream: Some(DecryptorBE32::from_aead(cipher, (&nonce).into())),
+        plain: vec![],
+        pos: 0,
+    }))
+}
+
+/// Encrypts a short string, such as a path stored in the database, to
+/// `recipient`.
+pub fn seal_str(recipient: &PublicKey, s: &str) -> String {
+    let (cipher, ephemeral_public) = ephemeral_cipher(recipient);
+    let mut nonce = [0u8; NONCE];
+    rand_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt((&nonce).into(), s.as_bytes())
+        .expect("encrypting in memory can't fail");
+    let mut sealed = ephemeral_public.to_vec();
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    format!("{}{}", SEALED_PREFIX, hex_encode(&sealed))
+}
+
+/// Reverses [`seal_str`]. Returns `None` if `s` isn't sealed or can't be
+/// opened with `key`.
+pub fn open_str(key: &SecretKey, s: &str) -> Option<String> {
+    let sealed = hex_decode(s.strip_prefix(SEALED_PREFIX)?)?;
+    if sealed.len() < 32 + NONCE {
+        return None;
+    }
+    let ephemeral_public: [u8; 32] = sealed[..32].try_into().unwrap();
+    let nonce: [u8; NONCE] = sealed[32..32 + NONCE].try_into().unwrap();
+    let cipher = recipient_cipher(key, ephemeral_public);
+    let plain = cipher
+        .decrypt((&nonce).into(), &sealed[32 + NONCE..])
+        .ok()?;
+    String::from_utf8(plain).ok()
+}
+
+pub fn is_sealed(s: &str) -> bool {
+    s.starts_with(SEALED_PREFIX)
+}
+
+/// 32 random bytes in hex, e.g. for a name which mustn't reveal anything
+pub(crate) fn random_hex() -> String {
+    let mut bytes = [0u8; 32];
+    rand_bytes(&mut bytes);
+    hex_encode(&bytes)
+}
+
+pub(crate) fn rand_bytes(buf: &mut [u8]) {
+    use chacha20poly1305::aead::rand_core::RngCore;
+    OsRng.fill_bytes(buf);
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let secret = derive(b"correct horse battery staple", b"0123456789abcdef").unwrap();
+        // a whole chunk leaves an empty last chunk
+        for len in [0, 10, CHUNK, CHUNK * 2 + 5] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut writer = Writer::new(Some(&secret.public_key()), Vec::new()).unwrap();
+            writer.write_all(&data).unwrap();
+            let sealed = writer.finish().unwrap();
+            let mut decrypted = vec![];
+            reader(Some(&secret), sealed.as_slice())
+                .unwrap()
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, data);
+
+            let truncated = &sealed[..sealed.len() - 1];
+            let mut sink = vec![];
+            assert!(reader(Some(&secret), truncated)
+                .unwrap()
+                .read_to_end(&mut sink)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_keyring() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keyring");
+        let (_, secret) = Keyring::create(&path, b"hunter2").unwrap();
+        assert!(Keyring::create(&path, b"hunter2").is_err());
+        let keyring = Keyring::load(&path).unwrap().unwrap();
+        assert!(keyring.unlock(b"hunter3").is_err());
+        let unlocked = keyring.unlock(b"hunter2").unwrap();
+
+        let sealed = seal_str(keyring.public_key(), "/home/me/.env");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains(".env"));
+        assert_eq!(open_str(&unlocked, &sealed).unwrap(), "/home/me/.env");
+        assert_eq!(open_str(&secret, &sealed).unwrap(), "/home/me/.env");
+    }
+}
@@ -0,0 +1,313 @@
+//! Storage compatible with the FreeDesktop.org Trash specification, so that
+//! desktop file managers and rim see each other's deleted files.
+//!
+//! A trash directory holds the payloads under `files/`, one
+//! `info/<name>.trashinfo` file per payload recording where it came from and
+//! when it was deleted, and a `directorysizes` cache for trashed
+//! directories.
+
+use crate::human::{format_local_time, parse_local_time};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Contents of a `.trashinfo` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashInfo {
+    pub original_path: PathBuf,
+    /// Seconds since the Unix epoch
+    pub deletion_date: u64,
+}
+
+impl TrashInfo {
+    pub fn parse(s: &str) -> Option<TrashInfo> {
+        let mut lines = s.lines().map(str::trim).filter(|l| !l.is_empty());
+        if lines.next()? != "[Trash Info]" {
+            return None;
+        }
+        let mut original_path: Option<PathBuf> = None;
+        let mut deletion_date: Option<u64> = None;
+        for line in lines {
+            if line.starts_with('[') {
+                break;
+            }
+            match line.split_once('=') {
+                Some(("Path", value)) => original_path = Some(percent_decode(value)),
+                Some(("DeletionDate", value)) => deletion_date = parse_local_time(value),
+                _ => (),
+            }
+        }
+        Some(TrashInfo {
+            original_path: original_path?,
+            deletion_date: deletion_date?,
+        })
+    }
+
+    pub fn deleted_now(original_path: &Path) -> TrashInfo {
+        TrashInfo {
+            original_path: original_path.components().collect(),
+            deletion_date: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(self.original_path.as_os_str().as_bytes()),
+            format_local_time(self.deletion_date),
+        )
+    }
+}
+
+/// The user's home trash, `$XDG_DATA_HOME/Trash`
+pub fn home_trash() -> Option<PathBuf> {
+    dirs_next::data_dir().map(|d| d.join("Trash"))
+}
+
+/// A payload name reserved in a trash directory by creating its info file
+#[derive(Debug)]
+pub struct Reservation {
+    pub files_path: PathBuf,
+    pub info_path: PathBuf,
+}
+
+/// Picks a name under `files/` which isn't taken yet and atomically claims
+/// it by creating the matching `.trashinfo` file, as the specification
+/// requires.
+pub fn reserve(trash: &Path, info: &TrashInfo) -> Result<Reservation, std::io::Error> {
+    let files = trash.join("files");
+    let infos = trash.join("info");
+    std::fs::create_dir_all(&files)?;
+    std::fs::create_dir_all(&infos)?;
+    let basename = info
+        .original_path
+        .file_name()
+        .ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Path has no file name",
+        ))?
+        .to_string_lossy()
+        .to_string();
+    for n in 1.. {
+        let name = if n == 1 {
+            basename.clone()
+        } else {
+            format!("{}.{}", basename, n)
+        };
+        let info_path = infos.join(format!("{}.trashinfo", name));
+        let mut info_file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        let files_path = files.join(&name);
+        if files_path.symlink_metadata().is_ok() {
+            // stray payload without an info file; leave it alone
+            drop(info_file);
+            std::fs::remove_file(&info_path)?;
+            continue;
+        }
+        if let Err(e) = info_file.write_all(info.serialize().as_bytes()) {
+            let _ = std::fs::remove_file(&info_path);
+            return Err(e);
+        }
+        return Ok(Reservation {
+            files_path,
+            info_path,
+        });
+    }
+    unreachable!()
+}
+
+/// Removes the info file and any `directorysizes` line for a payload which
+/// has left the trash.
+pub fn release(files_path: &Path) -> Result<(), std::io::Error> {
+    let (trash, name) = match split_files_path(files_path) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let info_path = trash
+        .join("info")
+        .join(format!("{}.trashinfo", name.to_string_lossy()));
+    match std::fs::remove_file(info_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    remove_directory_size(trash, name.as_bytes())
+}
+
+/// Returns `(trash, name)` if `path` is a payload at `trash/files/name`.
+pub fn split_files_path(path: &Path) -> Option<(&Path, &std::ffi::OsStr)> {
+    let name = path.file_name()?;
+    let files = path.parent()?;
+    if files.file_name()? != "files" {
+        return None;
+    }
+    Some((files.parent()?, name))
+}
+
+/// Lists the entries of a trash directory as `(payload path, info)` pairs,
+/// skipping info files which can't be parsed or have no payload.
+pub fn list(trash: &Path) -> Result<Vec<(PathBuf, TrashInfo)>, std::io::Error> {
+    let infos = trash.join("info");
+    let mut results = vec![];
+    let dir = match std::fs::read_dir(&infos) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(results),
+        Err(e) => return Err(e),
+    };
+    for entry in dir {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = match file_name
+            .to_str()
+            .and_then(|n| n.strip_suffix(".trashinfo"))
+        {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let info = match TrashInfo::parse(&std::fs::read_to_string(entry.path())?) {
+            Some(i) => i,
+            None => continue,
+        };
+        let files_path = trash.join("files").join(name);
+        if files_path.symlink_metadata().is_ok() {
+            results.push((files_path, info));
+        }
+    }
+    Ok(results)
+}
+
+/// Records a trashed directory in the `directorysizes` cache.
+pub fn add_directory_size(
+    trash: &Path,
+    name: &[u8],
+    size: u64,
+    info_path: &Path,
+) -> Result<(), std::io::Error> {
+    let info_mtime = info_path
+        .metadata()?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut lines = read_directory_sizes(trash)?;
+    lines.push(format!("{} {} {}", size, info_mtime, percent_encode(name)));
+    write_directory_sizes(trash, &lines)
+}
+
+fn remove_directory_size(trash: &Path, name: &[u8]) -> Result<(), std::io::Error> {
+    let encoded = percent_encode(name);
+    let lines = read_directory_sizes(trash)?;
+    let kept: Vec<String> = lines
+        .iter()
+        .filter(|l| l.splitn(3, ' ').nth(2) != Some(encoded.as_str()))
+        .cloned()
+        .collect();
+    if kept.len() == lines.len() {
+        return Ok(());
+    }
+    write_directory_sizes(trash, &kept)
+}
+
+fn read_directory_sizes(trash: &Path) -> Result<Vec<String>, std::io::Error> {
+    match std::fs::read_to_string(trash.join("directorysizes")) {
+        Ok(s) => Ok(s.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Rewrites the cache through a temporary file so readers never see it
+/// half-written.
+fn write_directory_sizes(trash: &Path, lines: &[String]) -> Result<(), std::io::Error> {
+    let tmp = trash.join(format!("directorysizes.{}", std::process::id()));
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, trash.join("directorysizes"))
+}
+
+/// Escapes a path the way the specification asks for (RFC 2396), leaving
+/// `/` and unreserved characters alone.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"/-_.~!*'()".contains(&b) {
+            s.push(b as char);
+        } else {
+            s.push_str(&format!("%{:02X}", b));
+        }
+    }
+    s
+}
+
+fn percent_decode(s: &str) -> PathBuf {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(b) = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    PathBuf::from(std::ffi::OsStr::from_bytes(&decoded))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trash_info_roundtrip() {
+        let info = TrashInfo {
+            original_path: PathBuf::from("/home/me/my file%.txt"),
+            deletion_date: 1709096470,
+        };
+        let serialized = info.serialize();
+        assert!(serialized.contains("Path=/home/me/my%20file%25.txt\n"));
+        assert_eq!(TrashInfo::parse(&serialized), Some(info));
+    }
+
+    #[test]
+    fn test_reserve_and_release() {
+        let trash = tempfile::tempdir().unwrap();
+        let info = TrashInfo {
+            original_path: PathBuf::from("/somewhere/notes"),
+            deletion_date: 1709096470,
+        };
+        let first = reserve(trash.path(), &info).unwrap();
+        let second = reserve(trash.path(), &info).unwrap();
+        assert_eq!(first.files_path, trash.path().join("files/notes"));
+        assert_eq!(second.files_path, trash.path().join("files/notes.2"));
+        assert!(second.info_path.ends_with("info/notes.2.trashinfo"));
+
+        std::fs::create_dir(&first.files_path).unwrap();
+        add_directory_size(trash.path(), b"notes", 42, &first.info_path).unwrap();
+        let listed = list(trash.path()).unwrap();
+        assert_eq!(listed, vec![(first.files_path.clone(), info)]);
+
+        release(&first.files_path).unwrap();
+        assert!(!first.info_path.exists());
+        let sizes = std::fs::read_to_string(trash.path().join("directorysizes")).unwrap();
+        assert!(sizes.is_empty());
+    }
+}
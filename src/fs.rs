@@ -1,8 +1,13 @@
+use crate::compression::{decoder, Codec, Compression, Encoder};
+use crate::crypto::{self, PublicKey, SecretKey};
 use blake3::Hasher;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use tar;
+use serde::Serialize;
+use std::os::unix::{
+    ffi::OsStrExt,
+    fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FileMetadata {
     pub original_path: String,
     pub is_dir: bool,
@@ -24,13 +29,16 @@ impl FileMetadata {
 }
 
 pub fn read_file_meta(path: &std::path::Path) -> Result<FileMetadata, std::io::Error> {
-    if !path.exists() {
+    // drop trailing slashes and `.` components
+    let path: std::path::PathBuf = path.components().collect();
+    let path = path.as_path();
+    if path.symlink_metadata().is_err() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "File not found",
         ));
     }
-    let metadata = path.metadata()?;
+    let metadata = path.symlink_metadata()?;
     let mtime: u64 = metadata
         .modified()?
         .duration_since(std::time::UNIX_EPOCH)
@@ -41,7 +49,6 @@ pub fn read_file_meta(path: &std::path::Path) -> Result<FileMetadata, std::io::E
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let blake3sum = blake3sum(path)?;
     let mut link_target: Option<String> = None;
     if metadata.is_symlink() {
         link_target = match std::fs::read_link(path) {
@@ -49,9 +56,19 @@ pub fn read_file_meta(path: &std::path::Path) -> Result<FileMetadata, std::io::E
             Err(_) => None,
         };
     }
+    // A symbolic link is identified by where it points, not by the contents
+    // of its target, which may not even exist.
+    let (blake3sum, file_size) = match &link_target {
+        Some(target) => (
+            blake3::hash(target.as_bytes()).to_hex().to_string(),
+            metadata.len(),
+        ),
+        None if metadata.is_dir() => tree_blake3sum(path)?,
+        None => (blake3sum(path)?, metadata.len()),
+    };
     Ok(FileMetadata {
         original_path: path.to_string_lossy().to_string(),
-        file_size: metadata.len(),
+        file_size,
         is_dir: metadata.is_dir(),
         link_target,
         blake3sum,
@@ -73,3 +90,436 @@ pub fn blake3sum(filename: &std::path::Path) -> Result<String, std::io::Error> {
     hex_string.push_str(&digest.to_hex());
     Ok(hex_string)
 }
+
+/// Whether a regular file or symbolic link (by its target) still has the
+/// blake3 `expected`. Anything unreadable doesn't.
+pub fn matches_blake3sum(path: &std::path::Path, expected: &str) -> bool {
+    match path.symlink_metadata() {
+        Ok(m) if m.is_symlink() => std::fs::read_link(path).is_ok_and(|target| {
+            blake3::hash(target.to_string_lossy().as_bytes())
+                .to_hex()
+                .as_str()
+                == expected
+        }),
+        Ok(m) if m.is_file() => blake3sum(path).is_ok_and(|hash| hash == expected),
+        _ => false,
+    }
+}
+
+/// Moves a regular file or symbolic link from `src` to `dest`. When the two
+/// live on different file systems and rename(2) fails with `EXDEV`, the file
+/// is copied instead, its blake3 is checked against `expected_blake3sum`, and
+/// `src` is only unlinked once the copy is verified and synced to disk.
+pub fn move_file(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    match std::fs::rename(src, dest) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_verify_unlink(src, dest, expected_blake3sum)
+        }
+        result => result,
+    }
+}
+
+/// The cross-device half of [`move_file`]
+pub fn copy_verify_unlink(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    copy_verified(src, dest, expected_blake3sum)?;
+    std::fs::remove_file(src)
+}
+
+/// Copies a regular file or symbolic link, preserving mode, owner and
+/// timestamps, and checks that the data copied has the expected blake3. The
+/// copy is written next to `dest` under a temporary name and renamed into
+/// place after verification, so `dest` never holds a partial file.
+pub fn copy_verified(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    let metadata = src.symlink_metadata()?;
+    write_atomically(dest, |partial| {
+        if metadata.is_symlink() {
+            copy_symlink(src, partial, expected_blake3sum)
+        } else {
+            copy_regular_file(src, partial, &metadata, expected_blake3sum)
+        }
+    })
+}
+
+/// Compresses and/or encrypts a regular file into `dest`, checking on the
+/// way that its contents still have the expected blake3. As with
+/// [`copy_verified`], `dest` only appears once it is complete and synced.
+/// Returns the size of the encoded file.
+pub fn encode_file(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    compression: Compression,
+    recipient: Option<&PublicKey>,
+    expected_blake3sum: &str,
+) -> Result<u64, std::io::Error> {
+    write_atomically(dest, |partial| {
+        let mut reader = std::fs::File::open(src)?;
+        let writer = std::fs::File::create(partial)?;
+        let sealed = crypto::Writer::new(recipient, std::io::BufWriter::new(writer))?;
+        let mut encoder = Encoder::new(compression, sealed)?;
+        copy_hashed(&mut reader, &mut encoder, expected_blake3sum, src)?;
+        let writer = encoder
+            .finish()?
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        writer.sync_all()?;
+        Ok(writer.metadata()?.len())
+    })
+}
+
+/// Reverses [`encode_file`], writing the original contents to `dest` and
+/// checking them against the blake3 recorded when the file was trashed.
+pub fn decode_file(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    codec: Codec,
+    key: Option<&SecretKey>,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    write_atomically(dest, |partial| {
+        let opened = crypto::reader(key, std::fs::File::open(src)?)?;
+        let reader = std::io::BufReader::new(opened);
+        let mut writer = std::fs::File::create(partial)?;
+        copy_hashed(
+            &mut decoder(codec, reader)?,
+            &mut writer,
+            expected_blake3sum,
+            src,
+        )?;
+        writer.sync_all()
+    })
+}
+
+/// Runs `write` on a temporary path next to `dest`, then renames the result
+/// into place and syncs the directory. The temporary file is removed if
+/// anything fails. Its name carries the pid, since another rim may be
+/// writing the same content-addressed object at the same time.
+fn write_atomically<T>(
+    dest: &std::path::Path,
+    write: impl FnOnce(&std::path::Path) -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(format!(".{}.part", std::process::id()));
+    let partial = std::path::PathBuf::from(partial);
+    let result = match write(&partial) {
+        Ok(result) => std::fs::rename(&partial, dest).map(|_| result),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+        return result;
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    result
+}
+
+/// Copies everything from `reader` to `writer`, failing if the data doesn't
+/// have the expected blake3.
+fn copy_hashed(
+    reader: &mut impl std::io::Read,
+    writer: &mut impl std::io::Write,
+    expected_blake3sum: &str,
+    src: &std::path::Path,
+) -> Result<(), std::io::Error> {
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+    }
+    if hasher.finalize().to_hex().as_str() != expected_blake3sum {
+        return Err(checksum_mismatch(src));
+    }
+    Ok(())
+}
+
+fn copy_symlink(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    let target = std::fs::read_link(src)?;
+    let digest = blake3::hash(target.to_string_lossy().as_bytes());
+    if digest.to_hex().as_str() != expected_blake3sum {
+        return Err(checksum_mismatch(src));
+    }
+    std::os::unix::fs::symlink(&target, dest)?;
+    let metadata = src.symlink_metadata()?;
+    // best effort, as for regular files
+    let _ = std::os::unix::fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid()));
+    Ok(())
+}
+
+fn copy_regular_file(
+    src: &std::path::Path,
+    dest: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    expected_blake3sum: &str,
+) -> Result<(), std::io::Error> {
+    let mut reader = std::fs::File::open(src)?;
+    let mut writer = std::fs::File::create(dest)?;
+    copy_hashed(&mut reader, &mut writer, expected_blake3sum, src)?;
+    // Like mv(1), keep the owner if allowed to, e.g. not for another user's
+    // file in a sticky directory, and drop the set-ID bits if not.
+    let mut mode = metadata.permissions().mode();
+    if std::os::unix::fs::fchown(&writer, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+        mode &= !0o6000;
+    }
+    writer.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    writer.set_times(
+        std::fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )?;
+    writer.sync_all()
+}
+
+fn checksum_mismatch(src: &std::path::Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Checksum mismatch while copying {}; it may have changed since it was hashed",
+            src.display()
+        ),
+    )
+}
+
+/// Makes `path` absolute and free of `.` and `..`, resolving symbolic
+/// links in its parent but not in the path itself, which is what a
+/// deletion of `path` acts on.
+pub fn resolve_parent(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+    let path: std::path::PathBuf = path.components().collect();
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(parent.canonicalize()?.join(name)),
+        _ => path.canonicalize(),
+    }
+}
+
+/// Returns the top directory of the file system containing `path`, i.e. the
+/// highest ancestor of `path` which is still on the same device.
+pub fn mount_point(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+    let dev = path.symlink_metadata()?.dev();
+    let mut top = path.to_path_buf();
+    for ancestor in path.ancestors().skip(1) {
+        if ancestor.as_os_str().is_empty() || ancestor.metadata()?.dev() != dev {
+            break;
+        }
+        top = ancestor.to_path_buf();
+    }
+    Ok(top)
+}
+
+/// Returns the per-mount trash directory, `$topdir/.rim-trash-$UID`, for a
+/// file which lives on a different file system than `global_trashdir`,
+/// creating it if needed. Returns `None` when the file should go to the
+/// global trash, either because it is on the same file system or because the
+/// per-mount directory can't be used safely: it must be a real directory
+/// owned by the current user and not accessible to anyone else.
+pub fn per_mount_trash_dir(
+    path: &std::path::Path,
+    global_trashdir: &std::path::Path,
+) -> Result<Option<std::path::PathBuf>, std::io::Error> {
+    let dev = path.symlink_metadata()?.dev();
+    if global_trashdir.metadata()?.dev() == dev {
+        return Ok(None);
+    }
+    let uid = unsafe { libc::getuid() };
+    let trashdir = mount_point(path)?.join(format!(".rim-trash-{}", uid));
+    match std::fs::DirBuilder::new().mode(0o700).create(&trashdir) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(_) => return Ok(None),
+    }
+    let metadata = trashdir.symlink_metadata()?;
+    let usable = metadata.is_dir()
+        && metadata.uid() == uid
+        && metadata.dev() == dev
+        && metadata.permissions().mode() & 0o077 == 0;
+    Ok(usable.then_some(trashdir))
+}
+
+/// Computes a blake3 hash over a whole directory tree, along with the total
+/// size of the regular files in it. Entries are visited in sorted order and
+/// contribute their relative path, type and content hash (or link target),
+/// so the digest is stable across runs and changes with any rename, edit or
+/// added/removed entry.
+pub fn tree_blake3sum(root: &std::path::Path) -> Result<(String, u64), std::io::Error> {
+    let mut hasher = Hasher::new();
+    let mut total_size: u64 = 0;
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries: Vec<std::fs::DirEntry> =
+            std::fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        // visit subdirectories in sorted order as well
+        let mut subdirs: Vec<std::path::PathBuf> = vec![];
+        for entry in entries {
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap();
+            let file_type = entry.file_type()?;
+            hasher.update(relative.as_os_str().as_bytes());
+            hasher.update(b"\0");
+            if file_type.is_symlink() {
+                hasher.update(b"l");
+                hasher.update(std::fs::read_link(&path)?.as_os_str().as_bytes());
+            } else if file_type.is_dir() {
+                hasher.update(b"d");
+                subdirs.push(path);
+            } else if file_type.is_file() {
+                hasher.update(b"f");
+                hasher.update(blake3sum(&path)?.as_bytes());
+                total_size += entry.metadata()?.len();
+            } else {
+                hasher.update(b"o");
+            }
+            hasher.update(b"\0");
+        }
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok((hasher.finalize().to_hex().to_string(), total_size))
+}
+
+/// Walks the directory tree under `root` without following symbolic links
+/// and returns the first directory which lives on a device other than
+/// `root_dev`, i.e. a mount point nested inside the tree.
+pub fn find_other_device(
+    root: &std::path::Path,
+    root_dev: u64,
+) -> Result<Option<std::path::PathBuf>, std::io::Error> {
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() {
+                continue;
+            }
+            if metadata.dev() != root_dev {
+                return Ok(Some(entry.path()));
+            }
+            pending.push(entry.path());
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the size of the file system containing `path` and how many bytes
+/// of it are available to unprivileged users.
+pub fn disk_space(path: &std::path::Path) -> Result<(u64, u64), std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let block = stat.f_frsize as u64;
+    Ok((stat.f_blocks as u64 * block, stat.f_bavail as u64 * block))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tree_blake3sum() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::write(dir.path().join("a/b/c.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("d.txt"), "world!").unwrap();
+        let (first, size) = tree_blake3sum(dir.path()).unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(first, tree_blake3sum(dir.path()).unwrap().0);
+
+        std::fs::rename(dir.path().join("d.txt"), dir.path().join("e.txt")).unwrap();
+        let (renamed, _) = tree_blake3sum(dir.path()).unwrap();
+        assert_ne!(first, renamed);
+    }
+
+    #[test]
+    fn test_copy_verify_unlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        std::fs::write(&src, "payload").unwrap();
+        std::fs::set_permissions(&src, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let meta = read_file_meta(&src).unwrap();
+
+        copy_verify_unlink(&src, &dest, &meta.blake3sum).unwrap();
+        assert!(!src.exists());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "payload");
+        let copied = read_file_meta(&dest).unwrap();
+        assert_eq!(copied.unix_mode, meta.unix_mode);
+        assert_eq!(copied.mtime, meta.mtime);
+    }
+
+    #[test]
+    fn test_copy_verify_unlink_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.txt");
+        let dest = dir.path().join("dest.txt");
+        std::fs::write(&src, "payload").unwrap();
+
+        let err = copy_verify_unlink(&src, &dest, "not-the-hash").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(src.exists());
+        assert!(!dest.exists());
+        // nothing but the source is left
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_mount_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("f");
+        std::fs::write(&file, "").unwrap();
+        let top = mount_point(&file).unwrap();
+        assert!(file.starts_with(&top));
+        assert_eq!(
+            top.metadata().unwrap().dev(),
+            file.metadata().unwrap().dev()
+        );
+        if let Some(parent) = top.parent() {
+            assert_ne!(
+                parent.metadata().unwrap().dev(),
+                top.metadata().unwrap().dev()
+            );
+        }
+    }
+
+    #[test]
+    fn test_per_mount_trash_dir_same_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("f");
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(per_mount_trash_dir(&file, dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_file_meta_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("f"), "1234").unwrap();
+        let with_slash = format!("{}/", dir.path().display());
+        let meta = read_file_meta(std::path::Path::new(&with_slash)).unwrap();
+        assert!(meta.is_dir);
+        assert_eq!(meta.file_size, 4);
+        assert_eq!(meta.original_path, dir.path().to_string_lossy());
+    }
+}
@@ -0,0 +1,299 @@
+//! Human-friendly formatting and parsing of sizes and times
+
+/// Formats a byte count with binary (IEC) units, e.g. `1.5 KiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+/// Describes `then` relative to `now` in the largest sensible unit, e.g.
+/// `5m ago` or `in 3d`.
+pub fn format_relative_time(then: u64, now: u64) -> String {
+    let (delta, past) = if then <= now {
+        (now - then, true)
+    } else {
+        (then - now, false)
+    };
+    if delta < 60 && past {
+        return "just now".to_string();
+    }
+    let amount = match delta {
+        d if d < 60 => format!("{}s", d),
+        d if d < 3600 => format!("{}m", d / 60),
+        d if d < 86400 => format!("{}h", d / 3600),
+        d if d < 14 * 86400 => format!("{}d", d / 86400),
+        d if d < 365 * 86400 => format!("{}w", d / (7 * 86400)),
+        d => format!("{}y", d / (365 * 86400)),
+    };
+    if past {
+        format!("{} ago", amount)
+    } else {
+        format!("in {}", amount)
+    }
+}
+
+/// Parses a point in time given on the command line: a Unix timestamp, a
+/// local date (`YYYY-MM-DD`), a local date and time (`YYYY-MM-DDThh:mm:ss`)
+/// or a duration meaning that long ago (`2d`).
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    if let Ok(timestamp) = s.parse::<u64>() {
+        return Ok(timestamp);
+    }
+    if let Some(t) = parse_local_time(s) {
+        return Ok(t);
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match parse_duration(s) {
+        Ok(ago) => Ok(now.saturating_sub(ago)),
+        Err(_) => Err(format!(
+            "invalid time '{}': expected YYYY-MM-DD, YYYY-MM-DDThh:mm:ss, a Unix timestamp or a duration like 2d",
+            s
+        )),
+    }
+}
+
+/// Parses a duration in seconds: a plain number of seconds, or numbers with
+/// a unit (`s`, `m`, `h`, `d`, `w` or `y`), e.g. `7d`, `36h` or `1h30m`.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration '{}': expected e.g. 7d, 36h or 2w", s);
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    let mut total: u64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = rest[digits..].trim_start();
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hour" | "hours" => 3600,
+            "d" | "day" | "days" => 86400,
+            "w" | "week" | "weeks" => 7 * 86400,
+            "y" | "year" | "years" => 365 * 86400,
+            _ => return Err(invalid()),
+        };
+        rest = rest[unit_len..].trim_start();
+        total = amount
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+    }
+    Ok(total)
+}
+
+/// Parses a size in bytes: a plain number of bytes, or a number with a unit,
+/// either binary (`KiB`, `MiB`, ..., or just `K`, `M`, ...) or decimal
+/// (`kB`, `MB`, ...), e.g. `500MiB` or `1.5G`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}': expected e.g. 500MiB or 2G", s);
+    let s = s.trim();
+    if let Ok(bytes) = s.parse::<u64>() {
+        return Ok(bytes);
+    }
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(invalid)?;
+    let amount: f64 = s[..split].parse().map_err(|_| invalid())?;
+    let unit = s[split..].trim();
+    let (prefix, base) = match unit.strip_suffix("iB") {
+        Some(prefix) => (prefix, 1024f64),
+        None => match unit.strip_suffix('B') {
+            Some(prefix) if !prefix.is_empty() => (prefix, 1000f64),
+            Some(prefix) => (prefix, 1f64),
+            None => (unit, 1024f64),
+        },
+    };
+    let power = match prefix {
+        "" => 0,
+        "k" | "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        "E" => 6,
+        _ => return Err(invalid()),
+    };
+    let bytes = amount * base.powi(power);
+    if bytes > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// Deserializers for config values which may be written for humans, like
+/// `ttl: 7d` or `min_size: 500MiB`, while still accepting plain integers.
+pub mod de {
+    use serde::{Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(u64),
+        Text(String),
+    }
+
+    fn parse<'de, D: Deserializer<'de>>(
+        raw: Raw,
+        parser: fn(&str) -> Result<u64, String>,
+    ) -> Result<u64, D::Error> {
+        match raw {
+            Raw::Number(n) => Ok(n),
+            Raw::Text(s) => parser(&s).map_err(serde::de::Error::custom),
+        }
+    }
+
+    pub fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        parse::<D>(Raw::deserialize(deserializer)?, super::parse_duration)
+    }
+
+    pub fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        parse::<D>(Raw::deserialize(deserializer)?, super::parse_size)
+    }
+
+    pub fn option_duration<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<Raw>::deserialize(deserializer)?
+            .map(|raw| parse::<D>(raw, super::parse_duration))
+            .transpose()
+    }
+
+    pub fn option_size<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<Raw>::deserialize(deserializer)?
+            .map(|raw| parse::<D>(raw, super::parse_size))
+            .transpose()
+    }
+}
+
+/// Formats a timestamp as `YYYY-MM-DDThh:mm:ss` in local time.
+pub fn format_local_time(secs: u64) -> String {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Parses `YYYY-MM-DDThh:mm:ss` or `YYYY-MM-DD` (midnight) in local time.
+pub fn parse_local_time(s: &str) -> Option<u64> {
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let date: Vec<i32> = date
+        .split('-')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<i32> = time
+        .split(':')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date[0] - 1900;
+    tm.tm_mon = date[1] - 1;
+    tm.tm_mday = date[2];
+    tm.tm_hour = time[0];
+    tm.tm_min = time[1];
+    tm.tm_sec = time[2];
+    tm.tm_isdst = -1;
+    let t = unsafe { libc::mktime(&mut tm) };
+    u64::try_from(t).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(200 * 1024 * 1024), "200 MiB");
+    }
+
+    #[test]
+    fn test_format_relative_time() {
+        let now = 1_000_000_000;
+        assert_eq!(format_relative_time(now - 10, now), "just now");
+        assert_eq!(format_relative_time(now - 300, now), "5m ago");
+        assert_eq!(format_relative_time(now - 3 * 86400, now), "3d ago");
+        assert_eq!(format_relative_time(now + 7 * 86400, now), "in 7d");
+        assert_eq!(format_relative_time(now + 30 * 86400, now), "in 4w");
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1709096470"), Ok(1709096470));
+        let midnight = parse_time("2024-02-28").unwrap();
+        assert_eq!(parse_time("2024-02-28T00:00:00"), Ok(midnight));
+        assert_eq!(format_local_time(midnight), "2024-02-28T00:00:00");
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("").is_err());
+        let two_days_ago = parse_time("2d").unwrap();
+        assert!(two_days_ago.abs_diff(parse_time("48h").unwrap()) <= 1);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("604800"), Ok(604800));
+        assert_eq!(parse_duration("7d"), Ok(604800));
+        assert_eq!(parse_duration("36h"), Ok(36 * 3600));
+        assert_eq!(parse_duration("2w"), Ok(14 * 86400));
+        assert_eq!(parse_duration("1h 30m"), Ok(5400));
+        assert!(parse_duration("7").is_ok());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("7 fortnights").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("  ").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("500MiB"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("500MB"), Ok(500_000_000));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5 KiB"), Ok(1536));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert!(parse_size("10%").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("").is_err());
+    }
+}
@@ -1,53 +1,455 @@
+/*
+ * LD_PRELOAD library which sends deletions to rim instead of letting them
+ * happen. unlink(2), unlinkat(2) (with or without AT_REMOVEDIR), rmdir(2)
+ * and remove(3) run `rim intercept` on the absolute path, its parent
+ * directory resolved with realpath(3), whose exit status says how it went:
+ *
+ *   0    the path was recycled
+ *   125  rim leaves the path alone, so the real call goes ahead
+ *   else the errno the call fails with
+ *
+ * With $RIM_PRELOAD_OVERWRITES set, content about to be destroyed in place
+ * is kept too: before rename(2), renameat(2) or renameat2(2) replaces a
+ * non-empty regular file, and before open(2), openat(2), creat(2) or
+ * fopen(3) for writing truncates one, `rim intercept --overwrite` puts a
+ * copy in the trash. The call then goes ahead, unless rim failed with an
+ * errno.
+ *
+ * The real call also goes ahead when rim can't be run at all, for paths
+ * under /proc, /sys and /dev, and for paths under the colon-separated
+ * prefixes in $RIM_PRELOAD_EXCLUDE, which must be resolved paths too. rim is looked up as $RIM_BIN, or on
+ * $PATH, and given $RIM_CONFIG if set. It runs without LD_PRELOAD, and with
+ * RIM_INTERCEPTING set, which makes any nested copy of this library stand
+ * aside.
+ */
+#define _GNU_SOURCE
+/* the fortified inline wrappers would clash with the definitions here */
+#undef _FORTIFY_SOURCE
 #include <dlfcn.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <limits.h>
+#include <spawn.h>
+#include <stdarg.h>
 #include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
 #include <unistd.h>
 
-static int (*original_unlink)(const char *) = NULL;
-static int (*original_unlinkat)(int fd, const char *path, int flag) = NULL;
+#define RIM_PASS 125
 
-int unlink(const char *pathname) {
-  printf("Intercepting unlink(2): %s\n", pathname);
-  if (original_unlink == NULL) {
-    void *libc_handle = dlopen("libc.dylib", RTLD_LAZY);
-    if (!libc_handle) {
-      fprintf(stderr, "could not find libc.dylib\n");
+extern char **environ;
+
+/* The real functions. unlink(2) and rmdir(2) are unlinkat(2) relative to
+ * the working directory, so every real deletion goes through unlinkat. */
+static int (*real_unlinkat)(int, const char *, int);
+static int (*real_renameat)(int, const char *, int, const char *);
+static int (*real_renameat2)(int, const char *, int, const char *, unsigned int);
+static int (*real_open)(const char *, int, ...);
+static int (*real_open64)(const char *, int, ...);
+static int (*real_openat)(int, const char *, int, ...);
+static int (*real_openat64)(int, const char *, int, ...);
+static int (*real___open_2)(const char *, int);
+static int (*real___open64_2)(const char *, int);
+static int (*real___openat_2)(int, const char *, int);
+static int (*real___openat64_2)(int, const char *, int);
+static FILE *(*real_fopen)(const char *, const char *);
+static FILE *(*real_fopen64)(const char *, const char *);
+
+/* Looks the real functions up, if that hasn't happened yet: other
+ * libraries' constructors may open files before this one's runs. */
+__attribute__((constructor)) static void resolve(void) {
+  static int resolved = 0;
+  if (resolved) {
+    return;
+  }
+  real_unlinkat = dlsym(RTLD_NEXT, "unlinkat");
+  real_renameat = dlsym(RTLD_NEXT, "renameat");
+  real_renameat2 = dlsym(RTLD_NEXT, "renameat2");
+  real_open = dlsym(RTLD_NEXT, "open");
+  real_open64 = dlsym(RTLD_NEXT, "open64");
+  real_openat = dlsym(RTLD_NEXT, "openat");
+  real_openat64 = dlsym(RTLD_NEXT, "openat64");
+  real___open_2 = dlsym(RTLD_NEXT, "__open_2");
+  real___open64_2 = dlsym(RTLD_NEXT, "__open64_2");
+  real___openat_2 = dlsym(RTLD_NEXT, "__openat_2");
+  real___openat64_2 = dlsym(RTLD_NEXT, "__openat64_2");
+  real_fopen = dlsym(RTLD_NEXT, "fopen");
+  real_fopen64 = dlsym(RTLD_NEXT, "fopen64");
+  resolved = 1;
+}
+
+#define REAL(name) (resolve(), real_##name)
+
+/* Resolves `path` relative to `dirfd` into `out`, with `.`, `..` and
+ * symbolic links resolved in its parent but not in its last component,
+ * which is what gets deleted or replaced. Every decision about the path is
+ * made on the result, so `..` can't lead out of an excluded directory.
+ * Returns -1 if that can't be done, in which case the path is left to the
+ * real call. */
+static int absolute_path(int dirfd, const char *path, char *out, size_t size) {
+  char joined[PATH_MAX];
+  char parent[PATH_MAX];
+  if (path[0] == '/') {
+    if (snprintf(joined, sizeof(joined), "%s", path) >= (int)sizeof(joined)) {
       return -1;
     }
-    original_unlink = (int (*)(const char *))dlsym(libc_handle, "unlink");
-    if (!original_unlink) {
-      fprintf(stderr, "could not find the symbol for unlink(2)\n");
-      dlclose(libc_handle);
+  } else {
+    char base[PATH_MAX];
+    if (dirfd == AT_FDCWD) {
+      if (getcwd(base, sizeof(base)) == NULL) {
+        return -1;
+      }
+    } else {
+      char link[64];
+      snprintf(link, sizeof(link), "/proc/self/fd/%d", dirfd);
+      ssize_t n = readlink(link, base, sizeof(base) - 1);
+      if (n < 0) {
+        return -1;
+      }
+      base[n] = '\0';
+    }
+    if (snprintf(joined, sizeof(joined), "%s/%s", base, path) >= (int)sizeof(joined)) {
       return -1;
     }
-    printf("Executing custom logic...\n");
-    dlclose(libc_handle);
   }
-  return original_unlink(pathname);
+  size_t len = strlen(joined);
+  while (len > 1 && joined[len - 1] == '/') {
+    joined[--len] = '\0';
+  }
+  char *slash = strrchr(joined, '/');
+  const char *name = slash + 1;
+  if (strcmp(name, "") == 0 || strcmp(name, ".") == 0 || strcmp(name, "..") == 0) {
+    /* not something which can be deleted by that name anyway */
+    return realpath(joined, out) != NULL && strlen(out) < size ? 0 : -1;
+  }
+  if (slash == joined) {
+    strcpy(parent, "/");
+  } else {
+    *slash = '\0';
+    if (realpath(joined, parent) == NULL) {
+      return -1;
+    }
+  }
+  const char *separator = strcmp(parent, "/") == 0 ? "" : "/";
+  return snprintf(out, size, "%s%s%s", parent, separator, name) < (int)size ? 0 : -1;
 }
 
-int unlinkat(int fd, const char *path, int flag) {
-  printf("intercepting unlinkat(2): (fd=%d, path=%s, flag=%d)\n", fd, path,
-         flag);
-  printf("Executing custom logic...");
-  if (original_unlinkat == NULL) {
-    void *libc_handle = dlopen("libc.dylib", RTLD_LAZY);
-    if (!libc_handle) {
-      return -1;
+/* Whether `path` is `prefix` or lies under it */
+static int under(const char *path, const char *prefix, size_t len) {
+  while (len > 1 && prefix[len - 1] == '/') {
+    len--;
+  }
+  return len > 0 && strncmp(path, prefix, len) == 0 &&
+         (path[len] == '\0' || path[len] == '/');
+}
+
+static int excluded(const char *path) {
+  static const char *builtin[] = {"/proc", "/sys", "/dev"};
+  for (size_t i = 0; i < sizeof(builtin) / sizeof(builtin[0]); i++) {
+    if (under(path, builtin[i], strlen(builtin[i]))) {
+      return 1;
+    }
+  }
+  const char *prefixes = getenv("RIM_PRELOAD_EXCLUDE");
+  while (prefixes != NULL && *prefixes != '\0') {
+    const char *end = strchrnul(prefixes, ':');
+    if (under(path, prefixes, (size_t)(end - prefixes))) {
+      return 1;
+    }
+    prefixes = *end == ':' ? end + 1 : end;
+  }
+  return 0;
+}
+
+/* Runs `rim intercept [flag] -- path`. Returns 0 if rim did its part,
+ * RIM_PASS if the real call should go ahead regardless, or -1 with errno
+ * set. */
+static int run_rim(const char *path, const char *flag) {
+  if (getenv("RIM_INTERCEPTING") != NULL || excluded(path)) {
+    return RIM_PASS;
+  }
+  const char *rim = getenv("RIM_BIN");
+  const char *config = getenv("RIM_CONFIG");
+  const char *argv[8];
+  int argc = 0;
+  argv[argc++] = rim != NULL ? rim : "rim";
+  if (config != NULL) {
+    argv[argc++] = "--config";
+    argv[argc++] = config;
+  }
+  argv[argc++] = "intercept";
+  if (flag != NULL) {
+    argv[argc++] = flag;
+  }
+  argv[argc++] = "--";
+  argv[argc++] = path;
+  argv[argc] = NULL;
+
+  size_t n = 0;
+  while (environ[n] != NULL) {
+    n++;
+  }
+  char **envp = malloc((n + 2) * sizeof(char *));
+  if (envp == NULL) {
+    return RIM_PASS;
+  }
+  size_t envc = 0;
+  for (size_t i = 0; i < n; i++) {
+    if (strncmp(environ[i], "LD_PRELOAD=", 11) != 0) {
+      envp[envc++] = environ[i];
     }
-    original_unlinkat =
-        (int (*)(int, const char *, int))dlsym(libc_handle, "unlinkat");
-    if (!original_unlinkat) {
-      dlclose(libc_handle);
+  }
+  envp[envc++] = "RIM_INTERCEPTING=1";
+  envp[envc] = NULL;
+
+  pid_t pid;
+  int spawned = posix_spawnp(&pid, argv[0], NULL, NULL, (char *const *)argv,
+                             envp);
+  free(envp);
+  if (spawned != 0) {
+    return RIM_PASS;
+  }
+  int status;
+  while (waitpid(pid, &status, 0) < 0) {
+    if (errno != EINTR) {
+      /* SIGCHLD is ignored, so the status is lost: judge by the result,
+       * which only tells for deletions */
+      struct stat st;
+      if (flag != NULL && strcmp(flag, "--overwrite") == 0) {
+        return 0;
+      }
+      if (lstat(path, &st) < 0 && errno == ENOENT) {
+        return 0;
+      }
+      errno = EIO;
       return -1;
     }
-    dlclose(libc_handle);
   }
-  int result = unlinkat(fd, path, flag);
-  return result;
+  if (!WIFEXITED(status)) {
+    errno = EIO;
+    return -1;
+  }
+  switch (WEXITSTATUS(status)) {
+  case 0:
+    return 0;
+  case RIM_PASS:
+  case 127: /* rim couldn't be executed */
+    return RIM_PASS;
+  default:
+    errno = WEXITSTATUS(status);
+    return -1;
+  }
+}
+
+static int intercept_unlinkat(int dirfd, const char *path, int flags) {
+  char abspath[PATH_MAX];
+  if (path != NULL && absolute_path(dirfd, path, abspath, sizeof(abspath)) == 0) {
+    int result = run_rim(abspath, flags & AT_REMOVEDIR ? "--dir" : NULL);
+    if (result != RIM_PASS) {
+      return result;
+    }
+  }
+  return REAL(unlinkat)(dirfd, path, flags);
+}
+
+int unlink(const char *path) {
+  return intercept_unlinkat(AT_FDCWD, path, 0);
+}
+
+int unlinkat(int dirfd, const char *path, int flags) {
+  return intercept_unlinkat(dirfd, path, flags);
+}
+
+int rmdir(const char *path) {
+  return intercept_unlinkat(AT_FDCWD, path, AT_REMOVEDIR);
+}
+
+/* glibc's remove(3) calls unlink and rmdir internally, bypassing the
+ * wrappers above, so it's intercepted on its own. */
+int remove(const char *path) {
+  struct stat st;
+  int dir = path != NULL && lstat(path, &st) == 0 && S_ISDIR(st.st_mode);
+  return intercept_unlinkat(AT_FDCWD, path, dir ? AT_REMOVEDIR : 0);
+}
+
+static int overwrites_enabled(void) {
+  const char *enabled = getenv("RIM_PRELOAD_OVERWRITES");
+  return enabled != NULL && *enabled != '\0' && strcmp(enabled, "0") != 0;
+}
+
+/* Has rim keep a copy of the file at `path`, if it's a non-empty regular
+ * file. `stat_flags` are those of fstatat(2), i.e. whether `path` itself or
+ * what it links to gets overwritten. Returns 0 if the overwrite may go
+ * ahead, or -1 with errno set. */
+static int keep_copy(int dirfd, const char *path, int stat_flags) {
+  struct stat st;
+  char abspath[PATH_MAX];
+  char resolved[PATH_MAX];
+  int saved_errno = errno;
+  if (path == NULL || fstatat(dirfd, path, &st, stat_flags) != 0 ||
+      !S_ISREG(st.st_mode) || st.st_size == 0 ||
+      absolute_path(dirfd, path, abspath, sizeof(abspath)) != 0) {
+    errno = saved_errno;
+    return 0;
+  }
+  const char *target = abspath;
+  if (!(stat_flags & AT_SYMLINK_NOFOLLOW) && realpath(abspath, resolved) != NULL) {
+    target = resolved;
+  }
+  if (run_rim(target, "--overwrite") < 0) {
+    return -1;
+  }
+  errno = saved_errno;
+  return 0;
 }
 
-__attribute__((constructor)) static void init_interceptor() {
-  printf("hello!\n");
-  original_unlinkat = dlsym(RTLD_NEXT, "unlinkat");
-  original_unlink = dlsym(RTLD_NEXT, "unlink");
+/* Before a rename replaces `newpath` */
+static int keep_replaced(int olddirfd, const char *oldpath, int newdirfd,
+                         const char *newpath, unsigned int flags) {
+  struct stat src, dst;
+  if (!overwrites_enabled() || (flags & (RENAME_NOREPLACE | RENAME_EXCHANGE))) {
+    return 0;
+  }
+  /* renaming a file onto another link to itself does nothing */
+  if (oldpath != NULL && newpath != NULL &&
+      fstatat(olddirfd, oldpath, &src, AT_SYMLINK_NOFOLLOW) == 0 &&
+      fstatat(newdirfd, newpath, &dst, AT_SYMLINK_NOFOLLOW) == 0 &&
+      src.st_dev == dst.st_dev && src.st_ino == dst.st_ino) {
+    return 0;
+  }
+  return keep_copy(newdirfd, newpath, AT_SYMLINK_NOFOLLOW);
+}
+
+/* Before an open with `flags` truncates `path` */
+static int keep_truncated(int dirfd, const char *path, int flags) {
+  if (!overwrites_enabled() || !(flags & O_TRUNC) ||
+      ((flags & O_CREAT) && (flags & O_EXCL))) {
+    return 0;
+  }
+  return keep_copy(dirfd, path, flags & O_NOFOLLOW ? AT_SYMLINK_NOFOLLOW : 0);
+}
+
+int rename(const char *oldpath, const char *newpath) {
+  if (keep_replaced(AT_FDCWD, oldpath, AT_FDCWD, newpath, 0) < 0) {
+    return -1;
+  }
+  return REAL(renameat)(AT_FDCWD, oldpath, AT_FDCWD, newpath);
+}
+
+int renameat(int olddirfd, const char *oldpath, int newdirfd,
+             const char *newpath) {
+  if (keep_replaced(olddirfd, oldpath, newdirfd, newpath, 0) < 0) {
+    return -1;
+  }
+  return REAL(renameat)(olddirfd, oldpath, newdirfd, newpath);
+}
+
+int renameat2(int olddirfd, const char *oldpath, int newdirfd,
+              const char *newpath, unsigned int flags) {
+  if (REAL(renameat2) == NULL) {
+    errno = ENOSYS;
+    return -1;
+  }
+  if (keep_replaced(olddirfd, oldpath, newdirfd, newpath, flags) < 0) {
+    return -1;
+  }
+  return real_renameat2(olddirfd, oldpath, newdirfd, newpath, flags);
+}
+
+/* The mode argument is only there with O_CREAT or O_TMPFILE */
+#define OPEN_MODE(flags, last)                                                 \
+  mode_t mode = 0;                                                             \
+  if ((flags & O_CREAT) || (flags & O_TMPFILE) == O_TMPFILE) {                 \
+    va_list args;                                                              \
+    va_start(args, last);                                                      \
+    mode = va_arg(args, mode_t);                                               \
+    va_end(args);                                                              \
+  }
+
+int open(const char *path, int flags, ...) {
+  OPEN_MODE(flags, flags);
+  if (keep_truncated(AT_FDCWD, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(open)(path, flags, mode);
+}
+
+int open64(const char *path, int flags, ...) {
+  OPEN_MODE(flags, flags);
+  if (keep_truncated(AT_FDCWD, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(open64)(path, flags, mode);
+}
+
+int openat(int dirfd, const char *path, int flags, ...) {
+  OPEN_MODE(flags, flags);
+  if (keep_truncated(dirfd, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(openat)(dirfd, path, flags, mode);
+}
+
+int openat64(int dirfd, const char *path, int flags, ...) {
+  OPEN_MODE(flags, flags);
+  if (keep_truncated(dirfd, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(openat64)(dirfd, path, flags, mode);
+}
+
+/* What programs built with _FORTIFY_SOURCE call instead of open(2) */
+int __open_2(const char *path, int flags) {
+  if (keep_truncated(AT_FDCWD, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(__open_2)(path, flags);
+}
+
+int __open64_2(const char *path, int flags) {
+  if (keep_truncated(AT_FDCWD, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(__open64_2)(path, flags);
+}
+
+int __openat_2(int dirfd, const char *path, int flags) {
+  if (keep_truncated(dirfd, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(__openat_2)(dirfd, path, flags);
+}
+
+int __openat64_2(int dirfd, const char *path, int flags) {
+  if (keep_truncated(dirfd, path, flags) < 0) {
+    return -1;
+  }
+  return REAL(__openat64_2)(dirfd, path, flags);
+}
+
+/* glibc's creat(2) and fopen(3) open files internally, bypassing the
+ * wrappers above */
+int creat(const char *path, mode_t mode) {
+  return open(path, O_CREAT | O_WRONLY | O_TRUNC, mode);
+}
+
+int creat64(const char *path, mode_t mode) {
+  return open64(path, O_CREAT | O_WRONLY | O_TRUNC, mode);
+}
+
+FILE *fopen(const char *path, const char *mode) {
+  if (mode != NULL && mode[0] == 'w' && keep_truncated(AT_FDCWD, path, O_TRUNC) < 0) {
+    return NULL;
+  }
+  return REAL(fopen)(path, mode);
+}
+
+FILE *fopen64(const char *path, const char *mode) {
+  if (mode != NULL && mode[0] == 'w' && keep_truncated(AT_FDCWD, path, O_TRUNC) < 0) {
+    return NULL;
+  }
+  return REAL(fopen64)(path, mode);
 }
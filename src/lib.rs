@@ -1,21 +1,86 @@
+pub mod compression;
 pub mod config;
-mod fs;
+pub mod crypto;
+mod freedesktop;
+pub mod fs;
+pub mod human;
 pub mod metadata_db;
+pub mod output;
+pub mod preload;
+pub mod protect;
+pub mod purge;
+pub mod rules;
 mod util;
-use metadata_db::{MetadataDB, TrashEntry};
-use regex::Regex;
+use compression::{Codec, Compression, Encoder};
+use crypto::{Keyring, PublicKey, SecretKey};
+use freedesktop::TrashInfo;
+use metadata_db::{ListFilter, MetadataDB, SortKey, Stored, TrashEntry};
 use std::{
-    io::BufWriter,
-    os::unix::fs::{chown, PermissionsExt},
-    path::PathBuf,
+    cell::OnceCell,
+    io::{BufReader, BufWriter, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
     rc::Rc,
 };
-use tar::Builder;
-use util::toposort_files;
+use tar::{Archive, Builder};
+
+/// What a run of [`App::run_maintenance`] did
+#[derive(Debug, Default)]
+pub struct MaintenanceReport {
+    /// Entries which were removed from the trash
+    pub purged: Vec<TrashEntry>,
+    /// Bytes of payload removed from disk, as stored
+    pub bytes_freed: u64,
+    /// Entries which couldn't be removed, with the reason; they stay in the
+    /// trash until the next run
+    pub failures: Vec<(TrashEntry, String)>,
+    /// Warnings about data which purging may not have destroyed
+    pub warnings: Vec<String>,
+}
+
+impl MaintenanceReport {
+    fn record(&mut self, entry: TrashEntry, swept: Result<(u64, Vec<String>), std::io::Error>) {
+        match swept {
+            Ok((freed, warnings)) => {
+                self.bytes_freed += freed;
+                self.add_warnings(warnings);
+                self.purged.push(entry);
+            }
+            Err(e) => self.failures.push((entry, e.to_string())),
+        }
+    }
+
+    fn add_warnings(&mut self, warnings: Vec<String>) {
+        for warning in warnings {
+            if !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
+    }
+}
+
+/// Overrides of the configured retention for a single deletion
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecycleOptions {
+    /// Keep the entry this many seconds instead of the configured `ttl`
+    pub ttl: Option<u64>,
+    /// Keep the entry until it's unpinned
+    pub pin: bool,
+    /// File the entry under this operation, from [`App::begin_operation`]
+    pub operation: Option<i64>,
+    /// Recycle it even if it's protected, see [`protect::check`]; the trash
+    /// itself stays off limits
+    pub allow_protected: bool,
+}
 
 pub struct App {
     pub config: Rc<config::Config>,
     metadata_db: MetadataDB,
+    /// The key encrypted payloads are recovered with, once unlocked
+    secret_key: OnceCell<SecretKey>,
 }
 
 impl App {
@@ -24,50 +89,308 @@ impl App {
         Ok(App {
             config,
             metadata_db,
+            secret_key: OnceCell::new(),
         })
     }
 
     pub fn recycle_dir(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
-        assert!(path.is_dir());
-        assert!(!path.is_symlink());
-        let meta = fs::read_file_meta(path)?;
-        // pack into tarball
-        let dest_archive = self.generate_trash_path(&meta);
-        let dest_archive_file = std::fs::File::create(&dest_archive)?;
-        let mut archive = Builder::new(BufWriter::new(dest_archive_file));
-        archive.append_dir_all(path.file_name().unwrap(), path)?;
-        archive.finish()?;
-        Ok(())
+        self.recycle_dir_with(path, &RecycleOptions::default())
+    }
+
+    /// Recycles the directory at `path` with everything in it. Fails with
+    /// `NotADirectory` if `path` is anything else, including a symbolic link
+    /// to a directory.
+    pub fn recycle_dir_with(
+        &self,
+        path: &std::path::Path,
+        options: &RecycleOptions,
+    ) -> Result<(), std::io::Error> {
+        if !path.symlink_metadata()?.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotADirectory,
+                "Not a directory",
+            ));
+        }
+        match options.allow_protected {
+            false => protect::check(&self.config, path)?,
+            true => protect::check_trash(&self.config, path)?,
+        }
+        let mut meta = fs::read_file_meta(path)?;
+        let rule = rules::find(&self.config.rules, &meta);
+        if self.deletes_now(rule, options) {
+            return self.delete_now(path);
+        }
+        let ttl = self.ttl_for(rule, options);
+        self.make_room_for(path, meta.file_size)?;
+        if let Some(trash) = self.freedesktop_storage() {
+            match self.recycle_dir_freedesktop(path, &trash, meta.clone(), ttl, options) {
+                Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+                result => return result,
+            }
+        }
+        // pack into a tarball next to the objects, then file it under its
+        // own hash
+        let objects = self.trash_dir_for(path).join("objects");
+        std::fs::create_dir_all(&objects)?;
+        let partial = objects.join(format!(".{}.tar.part", std::process::id()));
+        let compression = rule
+            .and_then(|r| r.compression)
+            .unwrap_or(self.config.compression);
+        let recipient = self.public_key()?;
+        self.seal_metadata(&mut meta)?;
+        let archive_hash = match write_dir_archive(path, &partial, compression, recipient.as_ref())
+        {
+            Ok(hash) => hash,
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        let ext = format!(
+            ".tar{}{}",
+            compression.codec.extension(),
+            if recipient.is_some() { ".enc" } else { "" }
+        );
+        let name = self.object_name(&archive_hash, recipient.is_some());
+        let dest_archive = self.object_path(path, &name, &ext);
+        let objects_subdir = dest_archive.parent().unwrap();
+        if let Err(e) = std::fs::create_dir_all(objects_subdir)
+            .and_then(|_| std::fs::rename(&partial, &dest_archive))
+            .and_then(|_| std::fs::File::open(objects_subdir)?.sync_all())
+        {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        let stored = Stored {
+            compression: compression.codec,
+            stored_size: dest_archive.metadata()?.len(),
+            encrypted: recipient.is_some(),
+        };
+        if let Err(e) = self.metadata_db.create(
+            meta,
+            &dest_archive,
+            stored,
+            ttl,
+            options.pin,
+            options.operation,
+        ) {
+            if self.metadata_db.refcount(&dest_archive) == Ok(0) {
+                let _ = std::fs::remove_file(&dest_archive);
+            }
+            return Err(std::io::Error::other(format!(
+                "Error creating metadata entry: {}",
+                e
+            )));
+        }
+        // The archive is durable and indexed at this point, so whatever part
+        // of the tree gets removed stays recoverable even if removal fails
+        // halfway through.
+        std::fs::remove_dir_all(path)
+    }
+
+    /// Moves a directory as-is into the FreeDesktop.org trash. Fails with
+    /// `EXDEV` without touching anything if the trash is on another file
+    /// system, in which case the caller archives it instead.
+    fn recycle_dir_freedesktop(
+        &self,
+        path: &std::path::Path,
+        trash: &std::path::Path,
+        meta: fs::FileMetadata,
+        ttl: u64,
+        options: &RecycleOptions,
+    ) -> Result<(), std::io::Error> {
+        let reservation = freedesktop::reserve(trash, &TrashInfo::deleted_now(path))?;
+        if let Err(e) = std::fs::rename(path, &reservation.files_path) {
+            let _ = freedesktop::release(&reservation.files_path);
+            return Err(e);
+        }
+        let file_size = meta.file_size;
+        let stored = Stored {
+            compression: Codec::None,
+            stored_size: file_size,
+            encrypted: false,
+        };
+        if let Err(e) = self.metadata_db.create(
+            meta,
+            &reservation.files_path,
+            stored,
+            ttl,
+            options.pin,
+            options.operation,
+        ) {
+            let _ = std::fs::rename(&reservation.files_path, path);
+            let _ = freedesktop::release(&reservation.files_path);
+            return Err(std::io::Error::other(format!(
+                "Error creating metadata entry: {}",
+                e
+            )));
+        }
+        let name = reservation.files_path.file_name().unwrap();
+        freedesktop::add_directory_size(trash, name.as_bytes(), file_size, &reservation.info_path)
     }
 
     pub fn recycle_file(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
-        let meta = fs::read_file_meta(path)?;
-        let trash_path = self.generate_trash_path(&meta);
-        let entry = match self.metadata_db.create(meta, &trash_path) {
+        self.recycle_file_with(path, &RecycleOptions::default())
+    }
+
+    pub fn recycle_file_with(
+        &self,
+        path: &std::path::Path,
+        options: &RecycleOptions,
+    ) -> Result<(), std::io::Error> {
+        self.trash_file(path, options, false)
+    }
+
+    /// Puts a copy of a file which is about to be overwritten into the
+    /// trash, leaving the file itself alone. Files the rules would delete
+    /// right away aren't kept.
+    pub fn snapshot_file(
+        &self,
+        path: &std::path::Path,
+        options: &RecycleOptions,
+    ) -> Result<(), std::io::Error> {
+        self.trash_file(path, options, true)
+    }
+
+    /// Files a file in the trash, moving it there unless `keep` asks for a
+    /// copy.
+    fn trash_file(
+        &self,
+        path: &std::path::Path,
+        options: &RecycleOptions,
+        keep: bool,
+    ) -> Result<(), std::io::Error> {
+        match (keep, options.allow_protected) {
+            (true, _) => (),
+            (false, false) => protect::check(&self.config, path)?,
+            (false, true) => protect::check_trash(&self.config, path)?,
+        }
+        let mut meta = fs::read_file_meta(path)?;
+        let rule = rules::find(&self.config.rules, &meta);
+        if self.deletes_now(rule, options) {
+            return match keep {
+                true => Ok(()),
+                false => self.delete_now(path),
+            };
+        }
+        let ttl = self.ttl_for(rule, options);
+        self.make_room_for(path, meta.file_size)?;
+        let freedesktop_trash = self.freedesktop_storage();
+        // A symlink has no data worth compressing or hiding, and file
+        // managers must be able to read the FreeDesktop.org trash.
+        let (compression, recipient) = match &freedesktop_trash {
+            None if !meta.is_link() => (
+                rule.and_then(|r| r.compression)
+                    .unwrap_or(self.config.compression),
+                self.public_key()?,
+            ),
+            _ => (Compression::default(), None),
+        };
+        // the hash the contents are checked against, even once it's sealed
+        let blake3sum = meta.blake3sum.clone();
+        if freedesktop_trash.is_none() {
+            self.seal_metadata(&mut meta)?;
+        }
+        let trash_path = match &freedesktop_trash {
+            Some(trash) => freedesktop::reserve(trash, &TrashInfo::deleted_now(path))?.files_path,
+            None => {
+                // symlinks are hashed by their target, so keep them apart
+                // from files which happen to contain the same bytes
+                let ext = if meta.is_link() {
+                    ".link".to_string()
+                } else if recipient.is_some() {
+                    format!("{}.enc", compression.codec.extension())
+                } else {
+                    compression.codec.extension().to_string()
+                };
+                let name = self.object_name(&blake3sum, recipient.is_some());
+                let object = self.object_path(path, &name, &ext);
+                std::fs::create_dir_all(object.parent().unwrap())?;
+                object
+            }
+        };
+        let release = || {
+            if freedesktop_trash.is_some() {
+                let _ = freedesktop::release(&trash_path);
+            }
+        };
+        // Objects only ever appear complete, via rename, so an existing one
+        // holds this content and the original can simply go. A plain object
+        // is the inode of a file trashed earlier though, which is checked
+        // again in case something still changed it since.
+        let encoded = compression.codec != Codec::None || recipient.is_some();
+        let exists = freedesktop_trash.is_none()
+            && trash_path.symlink_metadata().is_ok()
+            && (encoded || fs::matches_blake3sum(&trash_path, &blake3sum));
+        // other names for the inode would keep changing the object
+        let linked = freedesktop_trash.is_none() && path.symlink_metadata()?.nlink() > 1;
+        let stored_size = if exists && encoded {
+            trash_path.symlink_metadata()?.len()
+        } else if encoded {
+            fs::encode_file(
+                path,
+                &trash_path,
+                compression,
+                recipient.as_ref(),
+                &blake3sum,
+            )?
+        } else {
+            meta.file_size
+        };
+        let stored = Stored {
+            compression: compression.codec,
+            stored_size,
+            encrypted: recipient.is_some(),
+        };
+        let entry = match self.metadata_db.create(
+            meta,
+            &trash_path,
+            stored,
+            ttl,
+            options.pin,
+            options.operation,
+        ) {
             Ok(entry) => entry,
             Err(e) => {
-                println!("Error creating metadata entry: {}", e);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Error creating metadata entry",
-                ));
+                release();
+                if encoded && self.metadata_db.refcount(&trash_path) == Ok(0) {
+                    let _ = std::fs::remove_file(&trash_path);
+                }
+                return Err(std::io::Error::other(format!(
+                    "Error creating metadata entry: {}",
+                    e
+                )));
             }
         };
-        match std::fs::rename(path, trash_path) {
+        // A sweep may have removed the object after it was found; now that
+        // an entry references it, it stays.
+        let swept = exists && trash_path.symlink_metadata().is_err();
+        let moved = match (keep, exists || encoded) {
+            _ if swept => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "The object was swept in the meantime",
+            )),
+            (true, true) => Ok(()),
+            (true, false) => fs::copy_verified(path, &trash_path, &blake3sum),
+            (false, true) => std::fs::remove_file(path),
+            (false, false) if linked => fs::copy_verify_unlink(path, &trash_path, &blake3sum),
+            (false, false) => fs::move_file(path, &trash_path, &blake3sum),
+        };
+        match moved {
             Ok(_) => (),
             Err(e) => {
-                println!("Error moving file to trash: {}", e);
-                let _ = self.metadata_db.delete(entry.id);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Error moving file to trash",
-                ));
+                if self.metadata_db.delete(entry.id) == Ok(0) && freedesktop_trash.is_none() {
+                    let _ = purge::remove(&trash_path);
+                }
+                release();
+                // as it is, so that `rim intercept` reports the right errno
+                return Err(e);
             }
         }
         Ok(())
     }
 
-    pub fn recover_file(&self, id: i64) -> Result<(), std::io::Error> {
+    pub fn recover_file(&self, id: i64) -> Result<TrashEntry, std::io::Error> {
         let meta = match self.metadata_db.find_by_id(id) {
             Ok(Some(meta)) => meta,
             Ok(None) => {
@@ -77,54 +400,317 @@ impl App {
                 ));
             }
             Err(e) => {
-                println!("Error finding metadata entry: {}", e);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Error finding metadata entry",
-                ));
+                return Err(std::io::Error::other(format!(
+                    "Error finding metadata entry: {}",
+                    e
+                )));
             }
         };
+        if meta.stored.encrypted || is_sealed(&meta.metadata) {
+            self.secret_key()?;
+        }
+        let meta = self.reveal(vec![meta]).pop().unwrap();
         let original_path: std::path::PathBuf = PathBuf::from(&meta.metadata.original_path);
-        let trash_filename = self.generate_trash_path(&meta.metadata);
-        if original_path.exists() {
+        if original_path.symlink_metadata().is_ok() {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::AlreadyExists,
                 "File already exists",
             ));
         }
-        std::fs::rename(trash_filename, &original_path)?;
-        let perms: std::fs::Permissions = std::fs::Permissions::from_mode(meta.metadata.unix_mode);
-        std::fs::set_permissions(&original_path, perms)?;
-        chown(
+        let shared = self
+            .metadata_db
+            .refcount(&meta.trash_path)
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))?
+            > 1;
+        if meta.metadata.is_dir && !meta.trash_path.is_dir() {
+            self.unpack_dir(&meta)?;
+        } else if meta.stored.compression != Codec::None || meta.stored.encrypted {
+            let key = match meta.stored.encrypted {
+                true => Some(self.secret_key()?),
+                false => None,
+            };
+            fs::decode_file(
+                &meta.trash_path,
+                &original_path,
+                meta.stored.compression,
+                key,
+                &meta.metadata.blake3sum,
+            )?;
+        } else if shared {
+            fs::copy_verified(&meta.trash_path, &original_path, &meta.metadata.blake3sum)?;
+        } else {
+            fs::move_file(&meta.trash_path, &original_path, &meta.metadata.blake3sum)?;
+        }
+        if !meta.metadata.is_dir && !meta.metadata.is_link() {
+            restore_times(&original_path, &meta.metadata)?;
+        }
+        if !meta.metadata.is_link() {
+            let perms: std::fs::Permissions =
+                std::fs::Permissions::from_mode(meta.metadata.unix_mode);
+            std::fs::set_permissions(&original_path, perms)?;
+        }
+        lchown(
             original_path,
             Some(meta.metadata.uid),
             Some(meta.metadata.gid),
         )?;
+        match self.metadata_db.delete(meta.id) {
+            // the last reference is gone; drop what's left of the object,
+            // e.g. an archive which was unpacked rather than moved
+            Ok(0) => purge::remove(&meta.trash_path)?,
+            Ok(_) => (),
+            Err(e) => {
+                return Err(std::io::Error::other(format!(
+                    "Error deleting metadata entry: {}",
+                    e
+                )));
+            }
+        }
+        if self.is_freedesktop_payload(&meta.trash_path) {
+            freedesktop::release(&meta.trash_path)?;
+        }
+        Ok(meta)
+    }
+
+    /// Indexes entries which a desktop file manager put into the
+    /// FreeDesktop.org trash, so they can be listed and recovered like files
+    /// deleted with rim, and forgets entries whose payload was restored or
+    /// purged from there behind rim's back. Returns how many entries were
+    /// imported.
+    pub fn import_freedesktop_trash(&self) -> Result<usize, std::io::Error> {
+        let trash = match self.config.freedesktop_trashdir() {
+            Some(trash) => trash,
+            None => return Ok(0),
+        };
+        let known = self
+            .metadata_db
+            .find_by_trash_dir(&trash.join("files"))
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))?;
+        for entry in known.iter() {
+            if entry.trash_path.symlink_metadata().is_err() {
+                let _ = self.metadata_db.delete(entry.id);
+            }
+        }
+        let mut imported = 0;
+        for (files_path, info) in freedesktop::list(&trash)? {
+            if known.iter().any(|e| e.trash_path == files_path) {
+                continue;
+            }
+            let mut meta = fs::read_file_meta(&files_path)?;
+            meta.original_path = info.original_path.to_string_lossy().to_string();
+            let stored = Stored {
+                compression: Codec::None,
+                stored_size: meta.file_size,
+                encrypted: false,
+            };
+            match self.metadata_db.create_at(
+                meta,
+                &files_path,
+                stored,
+                info.deletion_date,
+                self.config.ttl,
+            ) {
+                Ok(_) => imported += 1,
+                Err(e) => eprintln!("Error importing {}: {}", files_path.display(), e),
+            }
+        }
+        Ok(imported)
+    }
+
+    /// The FreeDesktop.org trash, if new deletions should be stored there
+    fn freedesktop_storage(&self) -> Option<PathBuf> {
+        match self.config.storage {
+            config::Storage::Freedesktop => self.config.freedesktop_trashdir(),
+            config::Storage::Rim => None,
+        }
+    }
+
+    fn is_freedesktop_payload(&self, trash_path: &std::path::Path) -> bool {
+        match self.config.freedesktop_trashdir() {
+            Some(trash) => trash_path.parent() == Some(&trash.join("files")),
+            None => false,
+        }
+    }
+
+    /// Derives the secret key from the configured key file or a passphrase
+    /// and checks it against the keyring, so encrypted entries can be
+    /// recovered. Does nothing if the key is unlocked already.
+    pub fn unlock(&self) -> Result<(), std::io::Error> {
+        if self.secret_key.get().is_some() {
+            return Ok(());
+        }
+        let keyring = Keyring::load(&self.config.keyring_path())?.ok_or(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Encryption was never set up for this trash",
+        ))?;
+        let key_file = self
+            .config
+            .encryption
+            .as_ref()
+            .and_then(|e| e.key_file.as_deref());
+        let secret = keyring.unlock(&crypto::key_material(key_file, false)?)?;
+        let _ = self.secret_key.set(secret);
         Ok(())
     }
 
-    fn generate_trash_path(&self, meta: &crate::fs::FileMetadata) -> std::path::PathBuf {
-        let re = Regex::new(r"(?P<filename>.+?)(?P<ext>\.[^.]*)?$").unwrap();
-        let original_filename = meta.original_path.split('/').last().unwrap();
-        let tagged_filename = re
-            .replace(original_filename, |caps: &regex::Captures| {
-                format!(
-                    "{}_{}{}",
-                    &caps["filename"],
-                    &meta.blake3sum[0..7],
-                    &caps["ext"]
-                )
-            })
-            .to_string();
-        let mut trash_path = self.config.trashdir.clone();
-        trash_path.push(tagged_filename);
-        trash_path
+    fn secret_key(&self) -> Result<&SecretKey, std::io::Error> {
+        self.unlock()?;
+        Ok(self.secret_key.get().unwrap())
+    }
+
+    /// The key new payloads are encrypted to, if encryption is enabled. The
+    /// keyring is set up on first use, which asks for a new passphrase
+    /// unless a key file is configured.
+    fn public_key(&self) -> Result<Option<PublicKey>, std::io::Error> {
+        let encryption = match &self.config.encryption {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let keyring_path = self.config.keyring_path();
+        if let Some(keyring) = Keyring::load(&keyring_path)? {
+            return Ok(Some(keyring.public_key().clone()));
+        }
+        std::fs::create_dir_all(&self.config.trashdir)?;
+        let material = crypto::key_material(encryption.key_file.as_deref(), true)?;
+        let (keyring, secret) = Keyring::create(&keyring_path, &material)?;
+        let _ = self.secret_key.set(secret);
+        Ok(Some(keyring.public_key().clone()))
+    }
+
+    /// Whether deleting would have to ask for a passphrase first: the
+    /// keyring isn't set up yet and there is neither a key file nor
+    /// `$RIM_PASSPHRASE` to set it up with.
+    pub fn needs_passphrase(&self) -> bool {
+        match &self.config.encryption {
+            Some(encryption) => {
+                encryption.key_file.is_none()
+                    && std::env::var_os("RIM_PASSPHRASE").is_none()
+                    && !self.config.keyring_path().exists()
+            }
+            None => false,
+        }
+    }
+
+    fn seals_metadata(&self) -> bool {
+        self.config
+            .encryption
+            .as_ref()
+            .is_some_and(|e| e.encrypt_metadata)
+    }
+
+    /// Encrypts the original path, the hash and the link target of a new
+    /// entry if `encrypt_metadata` is set.
+    fn seal_metadata(&self, meta: &mut fs::FileMetadata) -> Result<(), std::io::Error> {
+        if !self.seals_metadata() {
+            return Ok(());
+        }
+        if let Some(recipient) = self.public_key()? {
+            meta.original_path = crypto::seal_str(&recipient, &meta.original_path);
+            meta.blake3sum = crypto::seal_str(&recipient, &meta.blake3sum);
+            meta.link_target = meta
+                .link_target
+                .as_ref()
+                .map(|target| crypto::seal_str(&recipient, target));
+        }
+        Ok(())
+    }
+
+    /// The name of the object holding content with blake3 `hash`. A name
+    /// derived from the content would let anyone with the trash check
+    /// whether it holds a known file, and deleting has no secret at hand to
+    /// key it with, so encrypted payloads and entries with sealed metadata
+    /// get random names instead and are never deduplicated.
+    fn object_name(&self, hash: &str, encrypted: bool) -> String {
+        match encrypted || self.seals_metadata() {
+            true => crypto::random_hex(),
+            false => hash.to_string(),
+        }
+    }
+
+    /// Decrypts metadata sealed by `encrypt_metadata` if the key is
+    /// unlocked, or can be without asking for a passphrase. Whatever can't
+    /// be decrypted is left sealed.
+    fn reveal(&self, mut entries: Vec<TrashEntry>) -> Vec<TrashEntry> {
+        let sealed = |e: &TrashEntry| is_sealed(&e.metadata);
+        if !entries.iter().any(sealed) {
+            return entries;
+        }
+        let has_key_file = self
+            .config
+            .encryption
+            .as_ref()
+            .is_some_and(|e| e.key_file.is_some());
+        if has_key_file {
+            let _ = self.unlock();
+        }
+        if let Some(key) = self.secret_key.get() {
+            for entry in entries.iter_mut().filter(|e| sealed(e)) {
+                let meta = &mut entry.metadata;
+                for field in [&mut meta.original_path, &mut meta.blake3sum]
+                    .into_iter()
+                    .chain(meta.link_target.as_mut())
+                {
+                    if let Some(plain) = crypto::open_str(key, field) {
+                        *field = plain;
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    /// Extracts a directory archive next to its original location. The
+    /// archive stays in the trash, since other entries may share it.
+    fn unpack_dir(&self, entry: &TrashEntry) -> Result<(), std::io::Error> {
+        let original_path = PathBuf::from(&entry.metadata.original_path);
+        let parent = original_path.parent().ok_or(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Trashed directory has no parent",
+        ))?;
+        std::fs::create_dir_all(parent)?;
+        let key = match entry.stored.encrypted {
+            true => Some(self.secret_key()?),
+            false => None,
+        };
+        let archive_file = crypto::reader(key, std::fs::File::open(&entry.trash_path)?)?;
+        let mut archive = Archive::new(compression::decoder(
+            entry.stored.compression,
+            BufReader::new(archive_file),
+        )?);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(true);
+        archive.set_preserve_mtime(true);
+        archive.set_overwrite(false);
+        archive.unpack(parent)
+    }
+
+    /// Where the object `name` from [`App::object_name`] is stored, e.g.
+    /// `objects/af/1349b…` under the trash directory for `original`. Every
+    /// entry with the same name shares the object.
+    fn object_path(&self, original: &std::path::Path, name: &str, ext: &str) -> PathBuf {
+        self.trash_dir_for(original)
+            .join("objects")
+            .join(&name[..2])
+            .join(format!("{}{}", &name[2..], ext))
+    }
+
+    /// Picks the directory a file is moved into: the per-mount trash of the
+    /// file system it lives on when `per_mount_trash` is enabled and usable,
+    /// otherwise the global trash directory.
+    fn trash_dir_for(&self, path: &std::path::Path) -> PathBuf {
+        if !self.config.per_mount_trash {
+            return self.config.trashdir.clone();
+        }
+        match fs::per_mount_trash_dir(path, &self.config.trashdir) {
+            Ok(Some(trashdir)) => trashdir,
+            _ => self.config.trashdir.clone(),
+        }
     }
 
     #[allow(dead_code)]
     fn id_from_trash_path(&self, path: &std::path::Path) -> Result<i64, std::io::Error> {
         let filename = path.file_name().unwrap().to_str().unwrap();
-        let id_str = filename.split('_').last().unwrap();
+        let id_str = filename.split('_').next_back().unwrap();
         let id = id_str
             .parse::<i64>()
             .expect("Invalid trash filename: Should have an integer id at the end of the filename");
@@ -133,11 +719,78 @@ impl App {
 
     pub fn list_recent(&self, n: u32) -> Result<Vec<TrashEntry>, std::io::Error> {
         let results = self.metadata_db.recent(n).expect("SQL error");
-        Ok(results)
+        Ok(self.reveal(results))
+    }
+
+    /// Finds the entries a user may mean by `query`: a numeric id, a prefix
+    /// of at least four hex characters of a blake3 hash, or an original path
+    /// (relative paths are resolved against `cwd`). Matches are ordered
+    /// newest first. An id match is returned on its own; otherwise hash and
+    /// path matches are combined, since a file name can look like hex too.
+    pub fn find_entries(
+        &self,
+        query: &str,
+        cwd: &std::path::Path,
+    ) -> Result<Vec<TrashEntry>, std::io::Error> {
+        let sql_error = |e: rusqlite::Error| std::io::Error::other(format!("SQL error: {}", e));
+        if let Ok(id) = query.parse::<i64>() {
+            if let Some(entry) = self.metadata_db.find_by_id(id).map_err(sql_error)? {
+                return Ok(vec![entry]);
+            }
+        }
+        let mut results = vec![];
+        let hash_prefix = query.to_ascii_lowercase();
+        if hash_prefix.len() >= 4 && hash_prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            results = self
+                .metadata_db
+                .find_by_hash_prefix(&hash_prefix)
+                .map_err(sql_error)?;
+        }
+        let abspath: PathBuf = cwd.join(query).components().collect();
+        for entry in self.metadata_db.find(&abspath).map_err(sql_error)? {
+            if !results.iter().any(|e| e.id == entry.id) {
+                results.push(entry);
+            }
+        }
+        // paths and hashes sealed by encrypt_metadata can only be compared
+        // decrypted
+        if self.config.encryption.is_some() {
+            let sealed = self
+                .metadata_db
+                .list(&ListFilter::default())
+                .map_err(sql_error)?
+                .into_iter()
+                .filter(|e| is_sealed(&e.metadata))
+                .collect();
+            for entry in self.reveal(sealed) {
+                let hash_matches = hash_prefix.len() >= 4
+                    && !is_sealed(&entry.metadata)
+                    && entry.metadata.blake3sum.starts_with(&hash_prefix);
+                if (hash_matches || Path::new(&entry.metadata.original_path) == abspath)
+                    && !results.iter().any(|e| e.id == entry.id)
+                {
+                    results.push(entry);
+                }
+            }
+        }
+        results.sort_by_key(|e| std::cmp::Reverse((e.created_at, e.id)));
+        Ok(self.reveal(results))
     }
 
-    /// Runs a maintenance task which permanently deletes the expired files.
-    pub fn run_maintenance(&self) -> Result<(), std::io::Error> {
+    pub fn list(&self, filter: &ListFilter) -> Result<Vec<TrashEntry>, std::io::Error> {
+        self.metadata_db
+            .list(filter)
+            .map(|entries| self.reveal(entries))
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))
+    }
+
+    /// Runs a maintenance task which permanently deletes the expired files,
+    /// then evicts more entries if the trash is over its size limits.
+    /// Each entry is swept on its own: its payload goes first, once no other
+    /// entry shares it, and then its row, so a payload which can't be removed
+    /// keeps its entry for the next run. Failures don't stop the sweep; they
+    /// are collected in the report instead.
+    pub fn run_maintenance(&self) -> Result<MaintenanceReport, std::io::Error> {
         let now: u64 = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -154,22 +807,866 @@ impl App {
                     "SQL error: {}, error={}, sql={}, offset={}",
                     msg, error, sql, offset
                 );
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "SQL Error"));
+                return Err(std::io::Error::other("SQL Error"));
             }
             Err(e) => {
                 eprintln!("SQL error: {}", e);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "SQL Error"));
+                return Err(std::io::Error::other("SQL Error"));
             }
         };
-        let realpaths: Vec<PathBuf> = expired.into_iter().map(|entry| entry.trash_path).collect();
-        let realpaths = toposort_files(&realpaths);
-        for realpath in realpaths.iter() {
-            if realpath.is_dir() {
-                std::fs::remove_dir(realpath)?;
-            } else {
-                std::fs::remove_file(realpath)?;
+        let mut report = MaintenanceReport::default();
+        for entry in self.reveal(expired) {
+            let swept = self.sweep(&entry);
+            report.record(entry, swept);
+        }
+        let evicted = self.make_room(None)?;
+        report.purged.extend(evicted.purged);
+        report.bytes_freed += evicted.bytes_freed;
+        report.failures.extend(evicted.failures);
+        report.add_warnings(evicted.warnings);
+        Ok(report)
+    }
+
+    /// Evicts entries, per the `eviction` policy, until the trash is within
+    /// `max_trash_size` and `min_free_space` with room for `incoming`: the
+    /// path about to be recycled and its size. An item which can't fit even
+    /// once everything else is gone is refused with
+    /// [`std::io::ErrorKind::StorageFull`] before anything is evicted.
+    ///
+    /// Payloads only take up and free space on their own file system, so the
+    /// limits hold for each one the trash spans (e.g. with `per_mount_trash`)
+    /// on its own: for `incoming`, the one it goes to; otherwise all of them.
+    pub fn make_room(
+        &self,
+        incoming: Option<(&Path, u64)>,
+    ) -> Result<MaintenanceReport, std::io::Error> {
+        let mut report = MaintenanceReport::default();
+        if self.config.max_trash_size.is_none() && self.config.min_free_space.is_none() {
+            return Ok(report);
+        }
+        let trash_dir = match (self.freedesktop_storage(), incoming) {
+            (Some(trash), _) => trash,
+            (None, Some((path, _))) => self.trash_dir_for(path),
+            (None, None) => self.config.trashdir.clone(),
+        };
+        let (sort, reverse) = match self.config.eviction {
+            config::Eviction::Oldest => (SortKey::Date, true),
+            config::Eviction::Largest => (SortKey::Size, false),
+        };
+        let entries = self.list(&ListFilter {
+            sort,
+            reverse,
+            ..Default::default()
+        })?;
+        // each file system with a directory on it to measure it by
+        let mut devices = vec![(trash_dir.metadata()?.dev(), trash_dir, vec![])];
+        for entry in entries {
+            let dir = match entry.trash_path.parent() {
+                Some(dir) => dir.to_path_buf(),
+                None => continue,
+            };
+            // a payload which went missing frees nothing
+            let dev = match dir.metadata() {
+                Ok(m) => m.dev(),
+                Err(_) => continue,
+            };
+            match devices.iter_mut().find(|(d, _, _)| *d == dev) {
+                Some((_, _, on_device)) => on_device.push(entry),
+                None => devices.push((dev, dir, vec![entry])),
             }
         }
+        if incoming.is_some() {
+            devices.truncate(1);
+        }
+        for (_, dir, entries) in devices {
+            self.make_room_on(&dir, entries, incoming, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// [`App::make_room`] for the file system holding `trash_dir`, whose
+    /// entries are `entries`, in the order they are to be evicted in.
+    fn make_room_on(
+        &self,
+        trash_dir: &Path,
+        entries: Vec<TrashEntry>,
+        incoming: Option<(&Path, u64)>,
+        report: &mut MaintenanceReport,
+    ) -> Result<(), std::io::Error> {
+        let size = incoming.map_or(0, |(_, size)| size);
+        let (disk_size, available) = fs::disk_space(trash_dir)?;
+        // a payload shared by several entries counts once
+        let mut payloads = std::collections::HashMap::new();
+        for entry in entries.iter() {
+            let stored = payloads.entry(&entry.trash_path).or_insert(0);
+            *stored = entry.stored.stored_size.max(*stored);
+        }
+        let stored: u64 = payloads.values().sum();
+        let quota = self.config.max_trash_size.map(|l| l.bytes(disk_size));
+        let reserve = self.config.min_free_space.map(|l| l.bytes(disk_size));
+        // The incoming item is counted as if it were copied, even though a
+        // file on the same file system is just renamed into the trash.
+        let needed = std::cmp::max(
+            quota.map_or(0, |q| (stored + size).saturating_sub(q)),
+            reserve.map_or(0, |r| (r + size).saturating_sub(available)),
+        );
+        if needed == 0 {
+            return Ok(());
+        }
+        let too_big = |path: &Path| {
+            std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!(
+                    "'{}' ({}) doesn't fit in the trash",
+                    path.display(),
+                    human::format_size(size)
+                ),
+            )
+        };
+        // pinned entries are never evicted
+        let candidates: Vec<TrashEntry> = entries.into_iter().filter(|e| !e.pinned).collect();
+        if let Some((path, _)) = incoming {
+            let evictable: u64 = candidates.iter().map(|e| e.stored.stored_size).sum();
+            if quota.is_some_and(|q| size > q) || needed > evictable {
+                return Err(too_big(path));
+            }
+        }
+        let mut freed = 0;
+        for entry in candidates {
+            if freed >= needed {
+                break;
+            }
+            let swept = self.sweep(&entry);
+            if let Ok((bytes, _)) = &swept {
+                freed += bytes;
+            }
+            report.record(entry, swept);
+        }
+        match incoming {
+            Some((path, _)) if freed < needed => Err(too_big(path)),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long an entry matching `rule` stays in the trash. The database
+    /// needs it to expire after it was created, so a `ttl` of 0 means "at
+    /// the next maintenance".
+    fn ttl_for(&self, rule: Option<&rules::Rule>, options: &RecycleOptions) -> u64 {
+        options
+            .ttl
+            .or(rule.and_then(|r| r.ttl))
+            .unwrap_or(self.config.ttl)
+            .max(1)
+    }
+
+    /// Whether a rule says to skip the trash. Asking for a `ttl` or a pin
+    /// explicitly wins over that.
+    fn deletes_now(&self, rule: Option<&rules::Rule>, options: &RecycleOptions) -> bool {
+        options.ttl.is_none()
+            && !options.pin
+            && rule.is_some_and(|r| r.action == rules::Action::Delete)
+    }
+
+    /// Deletes a file or directory for good instead of trashing it, as a
+    /// rule asked.
+    fn delete_now(&self, path: &Path) -> Result<(), std::io::Error> {
+        let purge = purge::Purge::from_config(&self.config);
+        for warning in purge::purge(path, &purge)? {
+            eprintln!("rim: warning: {}", warning);
+        }
+        Ok(())
+    }
+
+    fn make_room_for(&self, path: &Path, size: u64) -> Result<(), std::io::Error> {
+        for warning in self.make_room(Some((path, size)))?.warnings {
+            eprintln!("rim: warning: {}", warning);
+        }
         Ok(())
     }
+
+    /// Removes one entry for good, returning how many bytes of payload were
+    /// freed along with the warnings of purging it. The row is deleted in a
+    /// transaction which only commits once the payload is gone too, if no
+    /// other entry shares it, so a payload which can't be removed keeps its
+    /// entry.
+    fn sweep(&self, entry: &TrashEntry) -> Result<(u64, Vec<String>), std::io::Error> {
+        let swept = self.metadata_db.delete_with(entry.id, |remaining| {
+            if remaining > 0 {
+                return Ok((0, vec![]));
+            }
+            // a payload which went missing frees nothing
+            let freed = match entry.trash_path.symlink_metadata() {
+                Ok(_) => entry.stored.stored_size,
+                Err(_) => 0,
+            };
+            Ok((freed, self.purge_payload(&entry.trash_path)?))
+        })?;
+        Ok(swept.unwrap_or_default())
+    }
+
+    /// Permanently deletes an entry ahead of its expiration, destroying its
+    /// payload as `purge_mode` says unless other entries still share it.
+    /// Returns the entry along with warnings about data which may survive.
+    pub fn purge(&self, id: i64) -> Result<(TrashEntry, Vec<String>), std::io::Error> {
+        let sql_error = |e: rusqlite::Error| std::io::Error::other(format!("SQL error: {}", e));
+        let entry = self.find_entry(id)?;
+        let warnings = match self.metadata_db.delete(id).map_err(sql_error)? {
+            0 => self.purge_payload(&entry.trash_path)?,
+            n => vec![format!(
+                "the contents are kept, since {} other entr{} in the trash share them",
+                n,
+                if n == 1 { "y" } else { "ies" }
+            )],
+        };
+        Ok((entry, warnings))
+    }
+
+    /// Pins an entry, so it stays in the trash until it's unpinned, or
+    /// unpins it. An entry whose expiration passed while it was pinned gets
+    /// the configured `ttl` from now.
+    pub fn pin(&self, id: i64, pinned: bool) -> Result<TrashEntry, std::io::Error> {
+        let sql_error = |e: rusqlite::Error| std::io::Error::other(format!("SQL error: {}", e));
+        let entry = self.find_entry(id)?;
+        self.metadata_db.set_pinned(id, pinned).map_err(sql_error)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if !pinned && entry.expiration <= now {
+            self.metadata_db
+                .set_expiration(id, now + self.config.ttl.max(1))
+                .map_err(sql_error)?;
+        }
+        self.find_entry(id)
+    }
+
+    /// Keeps an entry in the trash `secs` longer, counting from now if it
+    /// has already expired.
+    pub fn extend(&self, id: i64, secs: u64) -> Result<TrashEntry, std::io::Error> {
+        let entry = self.find_entry(id)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.metadata_db
+            .set_expiration(id, entry.expiration.max(now) + secs)
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))?;
+        self.find_entry(id)
+    }
+
+    /// Starts an operation grouping the deletions `command` makes, e.g. one
+    /// run under rim-wrap. Pass its id in [`RecycleOptions`], and find its
+    /// entries with [`ListFilter::operation`]. The command line is sealed
+    /// like original paths are under `encrypt_metadata`.
+    pub fn begin_operation(&self, command: &str) -> Result<i64, std::io::Error> {
+        let command = match self.public_key()? {
+            Some(recipient) if self.seals_metadata() => crypto::seal_str(&recipient, command),
+            _ => command.to_string(),
+        };
+        self.metadata_db
+            .create_operation(&command)
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))
+    }
+
+    /// Looks up an entry, revealing its path if it can.
+    fn find_entry(&self, id: i64) -> Result<TrashEntry, std::io::Error> {
+        let entry = self
+            .metadata_db
+            .find_by_id(id)
+            .map_err(|e| std::io::Error::other(format!("SQL error: {}", e)))?
+            .ok_or(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "File not found in trash",
+            ))?;
+        Ok(self.reveal(vec![entry]).pop().unwrap())
+    }
+
+    fn purge_payload(&self, trash_path: &Path) -> Result<Vec<String>, std::io::Error> {
+        let warnings = purge::purge(trash_path, &purge::Purge::from_config(&self.config))?;
+        if self.is_freedesktop_payload(trash_path) {
+            freedesktop::release(trash_path)?;
+        }
+        Ok(warnings)
+    }
+}
+
+/// Packs the directory at `path` into a tarball at `dest`, compressed and
+/// encrypted as configured, flushing and fsyncing both the archive and the directory
+/// containing it before returning the blake3 of the uncompressed archive.
+fn write_dir_archive(
+    path: &std::path::Path,
+    dest: &std::path::Path,
+    compression: Compression,
+    recipient: Option<&PublicKey>,
+) -> Result<String, std::io::Error> {
+    let dest_file = std::fs::File::create(dest)?;
+    let sealed = crypto::Writer::new(recipient, BufWriter::new(dest_file))?;
+    let writer = HashingWriter {
+        inner: Encoder::new(compression, sealed)?,
+        hasher: blake3::Hasher::new(),
+    };
+    let mut archive = Builder::new(writer);
+    archive.follow_symlinks(false);
+    archive.append_dir_all(path.file_name().unwrap(), path)?;
+    let writer = archive.into_inner()?;
+    let hash = writer.hasher.finalize().to_hex().to_string();
+    let dest_file = writer
+        .inner
+        .finish()?
+        .finish()?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    dest_file.sync_all()?;
+    if let Some(parent) = dest.parent() {
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(hash)
+}
+
+/// Hashes everything written through it
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether any of the metadata `encrypt_metadata` hides is still sealed
+fn is_sealed(meta: &fs::FileMetadata) -> bool {
+    crypto::is_sealed(&meta.original_path) || crypto::is_sealed(&meta.blake3sum)
+}
+
+/// Puts back the timestamps recorded when a file was trashed, since an
+/// object shared by several entries only carries those of the first one.
+fn restore_times(path: &std::path::Path, meta: &fs::FileMetadata) -> Result<(), std::io::Error> {
+    let secs = |t: u64| std::time::UNIX_EPOCH + std::time::Duration::from_secs(t);
+    let current = path.symlink_metadata()?;
+    if current.mtime() as u64 == meta.mtime && current.atime() as u64 == meta.atime {
+        return Ok(());
+    }
+    std::fs::File::open(path)?.set_times(
+        std::fs::FileTimes::new()
+            .set_accessed(secs(meta.atime))
+            .set_modified(secs(meta.mtime)),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn setup() -> (tempfile::TempDir, App) {
+        setup_with(|_| ())
+    }
+
+    fn setup_with(customize: impl FnOnce(&mut config::Config)) -> (tempfile::TempDir, App) {
+        let root = tempfile::tempdir().unwrap();
+        let trashdir = root.path().join("trash");
+        std::fs::create_dir(&trashdir).unwrap();
+        let mut config = config::Config {
+            trashdir,
+            freedesktop_trashdir: Some(root.path().join("Trash")),
+            ..Default::default()
+        };
+        customize(&mut config);
+        let app = App::new(Rc::new(config)).unwrap();
+        (root, app)
+    }
+
+    #[test]
+    fn test_recycle_and_recover_dir() {
+        let (root, app) = setup();
+        let dir = root.path().join("project");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::set_permissions(dir.join("src"), std::fs::Permissions::from_mode(0o750)).unwrap();
+        app.recycle_dir(&dir).unwrap();
+
+        let entries = app.list_recent(10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].metadata.is_dir);
+        assert_eq!(entries[0].metadata.file_size, 12);
+        assert!(entries[0].trash_path.exists());
+
+        assert!(!dir.exists());
+        app.recover_file(entries[0].id).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/main.rs")).unwrap(),
+            "fn main() {}"
+        );
+        let mode = dir.join("src").metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        assert!(!entries[0].trash_path.exists());
+        assert!(app.list_recent(10).unwrap().is_empty());
+
+        // a directory swapped for something else is an error, not a panic
+        let link = root.path().join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        for path in [&link, &dir.join("src/main.rs")] {
+            let e = app.recycle_dir(path).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::NotADirectory);
+        }
+        assert!(dir.join("src/main.rs").exists());
+    }
+
+    #[test]
+    fn test_freedesktop_storage() {
+        let (root, app) = setup_with(|c| c.storage = config::Storage::Freedesktop);
+        let file = root.path().join("notes.txt");
+        let dir = root.path().join("photos");
+        std::fs::write(&file, "remember").unwrap();
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a.jpg"), "jpeg").unwrap();
+        app.recycle_file(&file).unwrap();
+        app.recycle_dir(&dir).unwrap();
+
+        let trash = root.path().join("Trash");
+        assert!(trash.join("files/notes.txt").is_file());
+        assert!(trash.join("files/photos/a.jpg").is_file());
+        assert!(trash.join("info/notes.txt.trashinfo").is_file());
+        let sizes = std::fs::read_to_string(trash.join("directorysizes")).unwrap();
+        assert!(sizes.starts_with("4 ") && sizes.trim_end().ends_with(" photos"));
+
+        for entry in app.list_recent(10).unwrap() {
+            app.recover_file(entry.id).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "remember");
+        assert!(dir.join("a.jpg").is_file());
+        assert!(!trash.join("info/notes.txt.trashinfo").exists());
+        assert!(!trash.join("info/photos.trashinfo").exists());
+    }
+
+    #[test]
+    fn test_import_freedesktop_trash() {
+        let (root, app) = setup();
+        let trash = root.path().join("Trash");
+        let original = root.path().join("report.pdf");
+        let info = TrashInfo {
+            original_path: original.clone(),
+            deletion_date: 1709096470,
+        };
+        let reservation = freedesktop::reserve(&trash, &info).unwrap();
+        std::fs::write(&reservation.files_path, "%PDF").unwrap();
+
+        assert_eq!(app.import_freedesktop_trash().unwrap(), 1);
+        assert_eq!(app.import_freedesktop_trash().unwrap(), 0);
+        let entries = app.list_recent(10).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].metadata.original_path,
+            original.to_string_lossy()
+        );
+
+        app.recover_file(entries[0].id).unwrap();
+        assert_eq!(std::fs::read_to_string(&original).unwrap(), "%PDF");
+        assert!(!reservation.info_path.exists());
+    }
+
+    #[test]
+    fn test_find_entries() {
+        let (root, app) = setup();
+        let file = root.path().join("notes.txt");
+        std::fs::write(&file, "v1").unwrap();
+        app.recycle_file(&file).unwrap();
+        let entry = &app.list_recent(1).unwrap()[0];
+
+        let by_id = app
+            .find_entries(&entry.id.to_string(), root.path())
+            .unwrap();
+        assert_eq!(by_id.len(), 1);
+        let by_hash = app
+            .find_entries(&entry.metadata.blake3sum[..6].to_uppercase(), root.path())
+            .unwrap();
+        assert_eq!(by_hash[0].id, entry.id);
+        let by_relative_path = app.find_entries("notes.txt", root.path()).unwrap();
+        assert_eq!(by_relative_path[0].id, entry.id);
+        let by_absolute_path = app
+            .find_entries(&file.to_string_lossy(), Path::new("/"))
+            .unwrap();
+        assert_eq!(by_absolute_path[0].id, entry.id);
+        assert!(app
+            .find_entries("other.txt", root.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_keeps_every_version() {
+        let (root, app) = setup();
+        let file = root.path().join("config.yaml");
+        for contents in ["same", "same", "different"] {
+            std::fs::write(&file, contents).unwrap();
+            app.recycle_file(&file).unwrap();
+        }
+        let versions = app.find_entries("config.yaml", root.path()).unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_ne!(versions[0].trash_path, versions[1].trash_path);
+        assert_eq!(versions[1].trash_path, versions[2].trash_path);
+        for version in versions.iter() {
+            assert!(version.trash_path.exists());
+        }
+
+        app.recover_file(versions[2].id).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "same");
+        assert!(versions[1].trash_path.exists());
+        assert_eq!(
+            app.find_entries("config.yaml", root.path()).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_maintenance_sweep() {
+        let (root, app) = setup_with(|c| c.ttl = 1);
+        let dir = root.path().join("build");
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(dir.join("out/app.o"), "object code").unwrap();
+        app.recycle_dir(&dir).unwrap();
+        let file = root.path().join("notes");
+        std::fs::write(&file, "gone already").unwrap();
+        app.recycle_file(&file).unwrap();
+        let entries = app.list_recent(10).unwrap();
+        let missing = entries.iter().find(|e| !e.metadata.is_dir).unwrap();
+        std::fs::remove_file(&missing.trash_path).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        let report = app.run_maintenance().unwrap();
+        assert_eq!(report.purged.len(), 2);
+        assert!(report.failures.is_empty());
+        // only the archive was still there to remove
+        let archive = entries.iter().find(|e| e.metadata.is_dir).unwrap();
+        assert_eq!(report.bytes_freed, archive.stored.stored_size);
+        assert!(entries.iter().all(|e| !e.trash_path.exists()));
+        assert!(app.list_recent(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_size_quota() {
+        let (root, app) = setup_with(|c| c.max_trash_size = Some(config::Limit::Bytes(250)));
+        for (name, byte) in [("a", b'a'), ("b", b'b'), ("c", b'c')] {
+            let file = root.path().join(name);
+            std::fs::write(&file, [byte; 100]).unwrap();
+            app.recycle_file(&file).unwrap();
+        }
+        let names = |app: &App| -> Vec<String> {
+            let mut names: Vec<String> = app
+                .list_recent(10)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.metadata.original_path)
+                .collect();
+            names.sort();
+            names
+        };
+        let path = |name: &str| root.path().join(name).to_string_lossy().to_string();
+        assert_eq!(names(&app), vec![path("b"), path("c")]);
+
+        let big = root.path().join("big");
+        std::fs::write(&big, [0u8; 300]).unwrap();
+        let e = app.recycle_file(&big).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+        assert!(big.exists());
+        assert_eq!(names(&app), vec![path("b"), path("c")]);
+    }
+
+    #[test]
+    fn test_size_quota_per_device() {
+        // needs a second file system to keep a payload on
+        let other = match tempfile::tempdir_in("/dev/shm") {
+            Ok(dir) => dir,
+            Err(_) => return,
+        };
+        let (root, app) = setup_with(|c| c.max_trash_size = Some(config::Limit::Bytes(250)));
+        if other.path().metadata().unwrap().dev() == root.path().metadata().unwrap().dev() {
+            return;
+        }
+        let payload = other.path().join("payload");
+        std::fs::write(&payload, [0u8; 200]).unwrap();
+        let mut meta = fs::read_file_meta(&payload).unwrap();
+        meta.original_path = other.path().join("old").to_string_lossy().to_string();
+        let stored = Stored {
+            stored_size: 200,
+            ..Default::default()
+        };
+        app.metadata_db
+            .create_at(meta, &payload, stored, 0, 1 << 40)
+            .unwrap();
+
+        // the oldest entry is on another file system, so evicting it
+        // wouldn't make room here, and it doesn't count against it either
+        for (name, byte) in [("a", b'a'), ("b", b'b')] {
+            let file = root.path().join(name);
+            std::fs::write(&file, [byte; 100]).unwrap();
+            app.recycle_file(&file).unwrap();
+        }
+        assert!(payload.exists());
+        assert_eq!(app.list_recent(10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_rules() {
+        let (root, app) = setup_with(|c| {
+            c.rules = vec![
+                rules::Rule {
+                    glob: Some("*.o".parse().unwrap()),
+                    action: rules::Action::Delete,
+                    ..Default::default()
+                },
+                rules::Rule {
+                    glob: Some("target/".parse().unwrap()),
+                    ttl: Some(86400),
+                    ..Default::default()
+                },
+            ]
+        });
+        let target = root.path().join("target");
+        std::fs::create_dir(&target).unwrap();
+        std::fs::write(target.join("main.o"), "object code").unwrap();
+        app.recycle_file(&target.join("main.o")).unwrap();
+        assert!(!target.join("main.o").exists());
+        assert!(app.list_recent(10).unwrap().is_empty());
+
+        std::fs::write(target.join("main"), "executable").unwrap();
+        app.recycle_dir(&target).unwrap();
+        let notes = root.path().join("notes");
+        std::fs::write(&notes, "keep me").unwrap();
+        app.recycle_file(&notes).unwrap();
+        for entry in app.list_recent(10).unwrap() {
+            let ttl = if entry.metadata.is_dir {
+                86400
+            } else {
+                app.config.ttl
+            };
+            assert_eq!(entry.expiration - entry.created_at, ttl);
+        }
+    }
+
+    #[test]
+    fn test_pin_and_extend() {
+        let (root, app) = setup_with(|c| c.ttl = 1);
+        let keep = root.path().join("keep");
+        std::fs::write(&keep, "pinned").unwrap();
+        let pin = RecycleOptions {
+            pin: true,
+            ..Default::default()
+        };
+        app.recycle_file_with(&keep, &pin).unwrap();
+        let later = root.path().join("later");
+        std::fs::write(&later, "a month").unwrap();
+        let month = RecycleOptions {
+            ttl: Some(30 * 86400),
+            ..Default::default()
+        };
+        app.recycle_file_with(&later, &month).unwrap();
+        let soon = root.path().join("soon");
+        std::fs::write(&soon, "default ttl").unwrap();
+        app.recycle_file(&soon).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        let report = app.run_maintenance().unwrap();
+        assert_eq!(report.purged.len(), 1);
+        assert_eq!(
+            report.purged[0].metadata.original_path,
+            soon.to_string_lossy()
+        );
+
+        let entries = app.list_recent(10).unwrap();
+        let pinned = entries.iter().find(|e| e.pinned).unwrap();
+        let other = entries.iter().find(|e| !e.pinned).unwrap();
+        assert_eq!(other.expiration - other.created_at, 30 * 86400);
+        let extended = app.extend(other.id, 86400).unwrap();
+        assert_eq!(extended.expiration, other.expiration + 86400);
+
+        // its expiration passed while pinned, so it gets a fresh ttl
+        let unpinned = app.pin(pinned.id, false).unwrap();
+        assert!(!unpinned.pinned);
+        assert!(unpinned.expiration > pinned.expiration);
+        assert_eq!(
+            app.pin(9999, true).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let (root, app) = setup();
+        let file = root.path().join("notes.txt");
+        std::fs::write(&file, "first draft").unwrap();
+        app.snapshot_file(&file, &RecycleOptions::default())
+            .unwrap();
+        // overwritten, as after `> notes.txt`
+        std::fs::write(&file, "second draft").unwrap();
+        app.snapshot_file(&file, &RecycleOptions::default())
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "second draft");
+
+        let entries = app.list(&ListFilter::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        std::fs::remove_file(&file).unwrap();
+        app.recover_file(entries[1].id).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "first draft");
+    }
+
+    #[test]
+    fn test_dedup_objects() {
+        let (root, app) = setup_with(|c| c.ttl = 1);
+        for checkout in ["a", "b"] {
+            let dir = root.path().join(checkout);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("LICENSE"), "MIT").unwrap();
+            app.recycle_file(&dir.join("LICENSE")).unwrap();
+        }
+        let entries = app.list_recent(10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].trash_path, entries[1].trash_path);
+        let hash = &entries[0].metadata.blake3sum;
+        assert_eq!(
+            entries[0].trash_path,
+            root.path()
+                .join("trash/objects")
+                .join(&hash[..2])
+                .join(&hash[2..])
+        );
+
+        app.recover_file(entries[0].id).unwrap();
+        assert!(entries[1].trash_path.exists());
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        assert_eq!(app.run_maintenance().unwrap().purged.len(), 1);
+        assert!(!entries[1].trash_path.exists());
+        assert!(app.list_recent(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dedup_checks_objects() {
+        let (root, app) = setup();
+        let file = root.path().join("LICENSE");
+        let link = root.path().join("COPYING");
+        std::fs::write(&file, "MIT").unwrap();
+        std::fs::hard_link(&file, &link).unwrap();
+        app.recycle_file(&file).unwrap();
+        let object = app.list_recent(1).unwrap()[0].trash_path.clone();
+        // the other name doesn't reach into the trash
+        std::fs::write(&link, "GPL").unwrap();
+        assert_eq!(std::fs::read_to_string(&object).unwrap(), "MIT");
+
+        // an object which changed anyway is replaced, not trusted
+        std::fs::write(&object, "BSD").unwrap();
+        std::fs::write(&file, "MIT").unwrap();
+        app.recycle_file(&file).unwrap();
+        assert_eq!(std::fs::read_to_string(&object).unwrap(), "MIT");
+        let newest = app.list_recent(1).unwrap()[0].id;
+        app.recover_file(newest).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "MIT");
+    }
+
+    #[test]
+    fn test_compression() {
+        let (root, app) = setup_with(|c| {
+            c.compression = Compression {
+                codec: Codec::Zstd,
+                level: Some(19),
+            }
+        });
+        let log = root.path().join("build.log");
+        let dir = root.path().join("target");
+        let contents = "warning: unused variable\n".repeat(1000);
+        std::fs::write(&log, &contents).unwrap();
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("out.txt"), &contents).unwrap();
+        app.recycle_file(&log).unwrap();
+        app.recycle_dir(&dir).unwrap();
+
+        let entries = app.list_recent(10).unwrap();
+        for entry in entries.iter() {
+            assert_eq!(entry.stored.compression, Codec::Zstd);
+            assert!(entry.stored.stored_size * 10 < entry.metadata.file_size);
+            assert!(entry.trash_path.to_string_lossy().ends_with(".zst"));
+            app.recover_file(entry.id).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), contents);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        let keydir = tempfile::tempdir().unwrap();
+        let key_file = keydir.path().join("key");
+        std::fs::write(&key_file, "not a very secret key").unwrap();
+        let (root, app) = setup_with(|c| {
+            c.encryption = Some(config::Encryption {
+                key_file: Some(key_file.clone()),
+                encrypt_metadata: true,
+            })
+        });
+        let env = root.path().join(".env");
+        let dir = root.path().join("keys");
+        std::fs::write(&env, "AWS_SECRET_ACCESS_KEY=hunter2").unwrap();
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("id_ed25519"), "PRIVATE KEY").unwrap();
+        let link = root.path().join("vault");
+        std::os::unix::fs::symlink("/srv/vault.kdbx", &link).unwrap();
+        let env_hash = fs::blake3sum(&env).unwrap();
+        app.recycle_file(&env).unwrap();
+        app.recycle_dir(&dir).unwrap();
+        app.recycle_file(&link).unwrap();
+        app.begin_operation("rm -rf ~/plans-for-world-domination")
+            .unwrap();
+
+        for entry in app.list_recent(10).unwrap() {
+            // nothing about the contents shows in the name either
+            let name = entry.trash_path.file_name().unwrap().to_string_lossy();
+            assert!(!name.contains(&entry.metadata.blake3sum[2..]));
+            if entry.metadata.is_link() {
+                continue;
+            }
+            assert!(entry.stored.encrypted);
+            let payload = std::fs::read(&entry.trash_path).unwrap();
+            let needle = if entry.metadata.is_dir {
+                "PRIVATE"
+            } else {
+                "hunter2"
+            };
+            assert!(!payload.windows(7).any(|w| w == needle.as_bytes()));
+        }
+        let db = std::fs::read(app.config.database_path()).unwrap();
+        assert!(!db.windows(4).any(|w| w == b".env"));
+        assert!(!db.windows(64).any(|w| w == env_hash.as_bytes()));
+        assert!(!db.windows(9).any(|w| w == b"vault.kdb"));
+        assert!(!db.windows(14).any(|w| w == b"world-dominati"));
+
+        let found = app.find_entries(".env", root.path()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].metadata.original_path, env.to_string_lossy());
+        let found = app.find_entries(&env_hash[..8], root.path()).unwrap();
+        assert_eq!(found[0].metadata.original_path, env.to_string_lossy());
+        for entry in app.list_recent(10).unwrap() {
+            app.recover_file(entry.id).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&env).unwrap(),
+            "AWS_SECRET_ACCESS_KEY=hunter2"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("id_ed25519")).unwrap(),
+            "PRIVATE KEY"
+        );
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            Path::new("/srv/vault.kdbx")
+        );
+    }
 }
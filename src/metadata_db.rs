@@ -1,22 +1,141 @@
-use rusqlite::{params, Connection, OpenFlags};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
 
+use crate::compression::Codec;
 use crate::config::Config;
 use crate::fs::FileMetadata;
-use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct TrashEntry {
     pub id: i64,
+    #[serde(flatten)]
     pub metadata: FileMetadata,
     pub trash_path: PathBuf,
+    /// When the file was deleted, in seconds since the Unix epoch
+    pub created_at: u64,
+    /// When the file will be permanently deleted, unless it's pinned
+    pub expiration: u64,
+    #[serde(flatten)]
+    pub stored: Stored,
+    /// Pinned entries stay in the trash until they are unpinned
+    pub pinned: bool,
+}
+
+/// How an entry's payload is kept on disk
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Stored {
+    pub compression: Codec,
+    /// Size of the payload on disk, as opposed to `file_size`
+    pub stored_size: u64,
+    /// Whether the payload is encrypted to the key in the keyring
+    pub encrypted: bool,
+}
+
+impl TrashEntry {
+    fn from_row(row: &rusqlite::Row) -> Result<TrashEntry, rusqlite::Error> {
+        Ok(TrashEntry {
+            id: row.get("id")?,
+            metadata: FileMetadata {
+                original_path: row.get("original_path")?,
+                file_size: row.get("file_size")?,
+                is_dir: row.get("is_dir")?,
+                link_target: row.get("link_target")?,
+                blake3sum: row.get("blake3sum")?,
+                mtime: row.get("mtime")?,
+                atime: row.get("atime")?,
+                unix_mode: row.get("unix_mode")?,
+                uid: row.get("uid")?,
+                gid: row.get("gid")?,
+            },
+            trash_path: row.get::<_, String>("trash_path")?.into(),
+            created_at: row.get("created_at")?,
+            expiration: row.get("expiration")?,
+            stored: Stored {
+                compression: row.get::<_, String>("compression")?.parse().map_err(
+                    |e: String| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            0,
+                            rusqlite::types::Type::Text,
+                            e.into(),
+                        )
+                    },
+                )?,
+                stored_size: row.get("stored_size")?,
+                encrypted: row.get("encrypted")?,
+            },
+            pinned: row.get("pinned")?,
+        })
+    }
+}
+
+/// What to sort trash listings by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Most recently deleted first
+    #[default]
+    Date,
+    /// Largest first
+    Size,
+    /// Alphabetically by original path
+    Path,
+}
+
+/// Filters for [`MetadataDB::list`]
+#[derive(Debug, Default, Clone)]
+pub struct ListFilter {
+    pub limit: Option<u32>,
+    /// Only entries deleted at or after this time
+    pub since: Option<u64>,
+    /// Only entries deleted at or before this time
+    pub until: Option<u64>,
+    /// Only entries whose original path is this or lies under it, compared
+    /// component by component
+    pub path_prefix: Option<String>,
+    /// Only entries whose original path is exactly this, i.e. every
+    /// trashed version of one file
+    pub original_path: Option<String>,
+    pub dirs_only: bool,
+    /// Only entries deleted as part of this operation, see
+    /// [`MetadataDB::create_operation`]
+    pub operation: Option<i64>,
+    pub sort: SortKey,
+    pub reverse: bool,
+}
+
+/// Schema changes made after `metadata_db.sql`, applied in order. The
+/// number of migrations already applied is kept in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_keep_versions.sql"),
+    include_str!("migrations/0002_blob_refcount.sql"),
+    include_str!("migrations/0003_compression.sql"),
+    include_str!("migrations/0004_encryption.sql"),
+    include_str!("migrations/0005_pinned.sql"),
+    include_str!("migrations/0006_operations.sql"),
+];
+
+/// Creates the schema if needed and brings it up to date.
+fn migrate(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(include_str!("metadata_db.sql"))?;
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let batch = format!(
+            "BEGIN;\n{}\nPRAGMA user_version = {};\nCOMMIT;",
+            migration,
+            i + 1
+        );
+        if let Err(e) = connection.execute_batch(&batch) {
+            let _ = connection.execute_batch("ROLLBACK;");
+            return Err(e);
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct MetadataDB {
     connection: Connection,
-    config: Rc<Config>,
 }
 
 impl MetadataDB {
@@ -25,9 +144,10 @@ impl MetadataDB {
             config.database_path(),
             OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
         )?;
-        let sql_string = std::include_str!("metadata_db.sql");
-        connection.execute_batch(sql_string)?;
-        Ok(MetadataDB { connection, config })
+        // several rim processes may write at once, e.g. under rim-wrap
+        connection.busy_timeout(std::time::Duration::from_secs(10))?;
+        migrate(&connection)?;
+        Ok(MetadataDB { connection })
     }
 
     pub(crate) fn recent(&self, n: u32) -> Result<Vec<TrashEntry>, rusqlite::Error> {
@@ -44,7 +164,13 @@ SELECT
     atime,
     unix_mode,
     uid,
-    gid
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
 FROM
     trash_entry
 ORDER BY
@@ -52,27 +178,7 @@ ORDER BY
 LIMIT :n
         "#;
         let mut stmt = self.connection.prepare(query)?;
-        let rows = stmt.query_map(&[(":n", &n.to_string())], |row| {
-            let id: i64 = row.get("id")?;
-            let metadata = FileMetadata {
-                original_path: row.get("original_path")?,
-                file_size: row.get("file_size")?,
-                is_dir: row.get("is_dir")?,
-                link_target: row.get("link_target")?,
-                blake3sum: row.get("blake3sum")?,
-                mtime: row.get("mtime")?,
-                atime: row.get("atime")?,
-                unix_mode: row.get("unix_mode")?,
-                uid: row.get("uid")?,
-                gid: row.get("gid")?,
-            };
-            let trash_path: PathBuf = row.get::<_, String>("trash_path")?.into();
-            Ok(TrashEntry {
-                id,
-                metadata,
-                trash_path,
-            })
-        })?;
+        let rows = stmt.query_map(&[(":n", &n.to_string())], TrashEntry::from_row)?;
         let mut results = Vec::new();
         for row in rows {
             results.push(row?);
@@ -80,14 +186,128 @@ LIMIT :n
         Ok(results)
     }
 
+    pub(crate) fn list(&self, filter: &ListFilter) -> Result<Vec<TrashEntry>, rusqlite::Error> {
+        let order = match (filter.sort, filter.reverse) {
+            (SortKey::Date, false) => "created_at DESC, id DESC",
+            (SortKey::Date, true) => "created_at ASC, id ASC",
+            (SortKey::Size, false) => "file_size DESC, id DESC",
+            (SortKey::Size, true) => "file_size ASC, id ASC",
+            (SortKey::Path, false) => "original_path ASC, id DESC",
+            (SortKey::Path, true) => "original_path DESC, id ASC",
+        };
+        let query = format!(
+            r#"
+SELECT
+    id,
+    original_path,
+    trash_path,
+    is_dir,
+    link_target,
+    file_size,
+    blake3sum,
+    mtime,
+    atime,
+    unix_mode,
+    uid,
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
+FROM
+    trash_entry
+WHERE
+    (:since IS NULL OR created_at >= :since)
+    AND (:until IS NULL OR created_at <= :until)
+    AND (:prefix IS NULL OR original_path = :prefix
+        OR substr(original_path, 1, length(rtrim(:prefix, '/')) + 1) = rtrim(:prefix, '/') || '/')
+    AND (:original_path IS NULL OR original_path = :original_path)
+    AND (NOT :dirs_only OR is_dir)
+    AND (:operation IS NULL OR operation = :operation)
+ORDER BY
+    {}
+LIMIT :limit
+"#,
+            order
+        );
+        let mut stmt = self.connection.prepare(&query)?;
+        let rows = stmt.query_map(
+            rusqlite::named_params! {
+                ":since": filter.since,
+                ":until": filter.until,
+                ":prefix": filter.path_prefix,
+                ":original_path": filter.original_path,
+                ":dirs_only": filter.dirs_only,
+                ":operation": filter.operation,
+                ":limit": filter.limit.map_or(-1, i64::from),
+            },
+            TrashEntry::from_row,
+        )?;
+        rows.collect()
+    }
+
+    /// Records a freshly trashed file, which expires after `ttl` seconds
+    /// unless `pinned`, as part of `operation` if given.
     pub(crate) fn create(
         &self,
         meta: FileMetadata,
         generated_path: &Path,
+        stored: Stored,
+        ttl: u64,
+        pinned: bool,
+        operation: Option<i64>,
+    ) -> Result<TrashEntry, rusqlite::Error> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let entry = TrashEntry {
+            id: 0,
+            metadata: meta,
+            trash_path: generated_path.into(),
+            created_at: now,
+            expiration: now + ttl,
+            stored,
+            pinned,
+        };
+        self.insert(entry, operation)
+    }
+
+    /// Like [`MetadataDB::create`], but for a file which was deleted at
+    /// `created_at` rather than now, e.g. one imported from another trash.
+    /// Such entries are neither pinned nor part of an operation.
+    pub(crate) fn create_at(
+        &self,
+        meta: FileMetadata,
+        generated_path: &Path,
+        stored: Stored,
+        created_at: u64,
+        ttl: u64,
+    ) -> Result<TrashEntry, rusqlite::Error> {
+        let entry = TrashEntry {
+            id: 0,
+            metadata: meta,
+            trash_path: generated_path.into(),
+            created_at,
+            expiration: created_at + ttl,
+            stored,
+            pinned: false,
+        };
+        self.insert(entry, None)
+    }
+
+    /// Inserts `entry`, all but its id, and returns it with the id it got.
+    fn insert(
+        &self,
+        mut entry: TrashEntry,
+        operation: Option<i64>,
     ) -> Result<TrashEntry, rusqlite::Error> {
         let query = r#"
 INSERT INTO
     trash_entry (
+        created_at,
         original_path,
         trash_path,
         is_dir,
@@ -99,10 +319,16 @@ INSERT INTO
         unix_mode,
         uid,
         gid,
-        expiration
+        expiration,
+        compression,
+        stored_size,
+        encrypted,
+        pinned,
+        operation
     )
 VALUES
     (
+        :created_at,
         :original_path,
         :trash_path,
         :is_dir,
@@ -114,19 +340,22 @@ VALUES
         :unix_mode,
         :uid,
         :gid,
-        :expiration
+        :expiration,
+        :compression,
+        :stored_size,
+        :encrypted,
+        :pinned,
+        :operation
     )
 "#;
-        let expiration = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            + self.config.ttl;
-        let rows_changed = self.connection.execute(
+        let meta = &entry.metadata;
+        let transaction = self.connection.unchecked_transaction()?;
+        let rows_changed = transaction.execute(
             query,
             params![
+                &entry.created_at.to_string(),
                 &meta.original_path,
-                &generated_path.to_string_lossy().to_string(),
+                &entry.trash_path.to_string_lossy().to_string(),
                 meta.is_dir,
                 meta.link_target,
                 &meta.file_size.to_string(),
@@ -136,34 +365,157 @@ VALUES
                 &meta.unix_mode.to_string(),
                 &meta.uid.to_string(),
                 &meta.gid.to_string(),
-                &expiration.to_string(),
+                &entry.expiration.to_string(),
+                entry.stored.compression.as_str(),
+                &entry.stored.stored_size.to_string(),
+                entry.stored.encrypted,
+                entry.pinned,
+                operation,
             ],
         )?;
         if rows_changed == 0 {
             return Err(rusqlite::Error::QueryReturnedNoRows);
         }
-        let inserted_id = self.connection.last_insert_rowid();
-        Ok(TrashEntry {
-            metadata: meta,
-            trash_path: generated_path.into(),
-            id: inserted_id,
-        })
+        entry.id = transaction.last_insert_rowid();
+        let blob_query = r#"
+INSERT INTO
+    blob (path, refcount)
+VALUES
+    (:path, 1)
+ON CONFLICT (path) DO UPDATE SET
+    refcount = refcount + 1
+"#;
+        transaction.execute(
+            blob_query,
+            &[(":path", &entry.trash_path.to_string_lossy())],
+        )?;
+        transaction.commit()?;
+        Ok(entry)
+    }
+
+    /// Starts a new operation for `command`, returning its id. Entries
+    /// created with that id belong to it.
+    pub(crate) fn create_operation(&self, command: &str) -> Result<i64, rusqlite::Error> {
+        self.connection.execute(
+            "INSERT INTO operation (command) VALUES (:command)",
+            rusqlite::named_params! {":command": command},
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    /// Pins or unpins an entry, returning false if there's no such entry.
+    pub(crate) fn set_pinned(&self, id: i64, pinned: bool) -> Result<bool, rusqlite::Error> {
+        let rows_changed = self.connection.execute(
+            "UPDATE trash_entry SET pinned = :pinned WHERE id = :id",
+            rusqlite::named_params! {":pinned": pinned, ":id": id},
+        )?;
+        Ok(rows_changed > 0)
+    }
+
+    /// Moves an entry's expiration, returning false if there's no such
+    /// entry.
+    pub(crate) fn set_expiration(&self, id: i64, expiration: u64) -> Result<bool, rusqlite::Error> {
+        let rows_changed = self.connection.execute(
+            "UPDATE trash_entry SET expiration = :expiration WHERE id = :id",
+            rusqlite::named_params! {":expiration": expiration, ":id": id},
+        )?;
+        Ok(rows_changed > 0)
+    }
+
+    /// Deletes an entry and drops its reference to the payload. Returns how
+    /// many entries still reference the payload; when that's zero, the
+    /// caller should remove it from disk.
+    pub(crate) fn delete(&self, trash_entry_id: i64) -> Result<u64, rusqlite::Error> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let remaining = Self::delete_in(&transaction, trash_entry_id)?;
+        transaction.commit()?;
+        Ok(remaining.unwrap_or(0))
+    }
+
+    /// Deletes an entry like [`MetadataDB::delete`], but only commits once
+    /// `release` succeeds, given how many entries still reference the
+    /// payload. Other processes can't add references to the payload in
+    /// the meantime. Returns `None` if there's no such entry.
+    pub(crate) fn delete_with<T>(
+        &self,
+        trash_entry_id: i64,
+        release: impl FnOnce(u64) -> Result<T, std::io::Error>,
+    ) -> Result<Option<T>, std::io::Error> {
+        let sql_error = |e: rusqlite::Error| std::io::Error::other(format!("SQL error: {}", e));
+        let transaction = rusqlite::Transaction::new_unchecked(
+            &self.connection,
+            rusqlite::TransactionBehavior::Immediate,
+        )
+        .map_err(sql_error)?;
+        let released = match Self::delete_in(&transaction, trash_entry_id).map_err(sql_error)? {
+            Some(remaining) => release(remaining)?,
+            None => return Ok(None),
+        };
+        transaction.commit().map_err(sql_error)?;
+        Ok(Some(released))
     }
 
-    pub(crate) fn delete(&self, trash_entry_id: i64) -> Result<(), rusqlite::Error> {
+    fn delete_in(
+        transaction: &rusqlite::Transaction,
+        trash_entry_id: i64,
+    ) -> Result<Option<u64>, rusqlite::Error> {
         let query = r#"
 DELETE FROM
     trash_entry
 WHERE
     id = :id
+RETURNING
+    trash_path
+"#;
+        let trash_path: Option<String> = transaction
+            .query_row(query, &[(":id", &trash_entry_id)], |row| row.get(0))
+            .optional()?;
+        let trash_path = match trash_path {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let blob_query = r#"
+UPDATE
+    blob
+SET
+    refcount = refcount - 1
+WHERE
+    path = :path
+RETURNING
+    refcount
 "#;
-        let _ = self
+        let remaining: u64 = transaction
+            .query_row(blob_query, &[(":path", &trash_path)], |row| row.get(0))
+            .optional()?
+            .unwrap_or(0);
+        if remaining == 0 {
+            transaction.execute(
+                "DELETE FROM blob WHERE path = :path",
+                &[(":path", &trash_path)],
+            )?;
+        }
+        Ok(Some(remaining))
+    }
+
+    /// How many entries reference the payload at `trash_path`
+    pub(crate) fn refcount(&self, trash_path: &Path) -> Result<u64, rusqlite::Error> {
+        let query = r#"
+SELECT
+    refcount
+FROM
+    blob
+WHERE
+    path = :path
+"#;
+        let refcount = self
             .connection
-            .execute(query, &[(":id", &trash_entry_id)])?;
-        Ok(())
+            .query_row(query, &[(":path", &trash_path.to_string_lossy())], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(refcount.unwrap_or(0))
     }
 
-    #[allow(dead_code)]
     pub(crate) fn find(
         &self,
         abspath: &std::path::Path,
@@ -181,33 +533,26 @@ SELECT
     atime,
     unix_mode,
     uid,
-    gid
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
 FROM
     trash_entry
 WHERE
     original_path = :original_path
 ORDER BY
-    created_at DESC
+    created_at DESC,
+    id DESC
         "#;
         let mut stmt = self.connection.prepare(query)?;
-        let rows = stmt.query_map(&[(":abspath", &abspath.to_string_lossy())], |row| {
-            Ok(TrashEntry {
-                id: row.get("id")?,
-                metadata: FileMetadata {
-                    original_path: row.get("original_path")?,
-                    file_size: row.get("file_size")?,
-                    is_dir: row.get("is_dir")?,
-                    link_target: row.get("link_target")?,
-                    blake3sum: row.get("blake3sum")?,
-                    mtime: row.get("mtime")?,
-                    atime: row.get("atime")?,
-                    unix_mode: row.get("unix_mode")?,
-                    uid: row.get("uid")?,
-                    gid: row.get("gid")?,
-                },
-                trash_path: row.get::<_, String>("trash_path")?.into(),
-            })
-        })?;
+        let rows = stmt.query_map(
+            &[(":original_path", &abspath.to_string_lossy())],
+            TrashEntry::from_row,
+        )?;
         let mut results = Vec::new();
         for row in rows {
             results.push(row?);
@@ -215,6 +560,82 @@ ORDER BY
         Ok(results)
     }
 
+    /// Finds entries whose blake3 starts with `prefix` (lowercase hex). The
+    /// first 7 characters go through `file_hash_slug_idx`.
+    pub(crate) fn find_by_hash_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<TrashEntry>, rusqlite::Error> {
+        let query = r#"
+SELECT
+    id,
+    original_path,
+    trash_path,
+    is_dir,
+    link_target,
+    file_size,
+    blake3sum,
+    mtime,
+    atime,
+    unix_mode,
+    uid,
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
+FROM
+    trash_entry
+WHERE
+    (length(:prefix) < 7 OR substr(blake3sum, 1, 7) = substr(:prefix, 1, 7))
+    AND substr(blake3sum, 1, length(:prefix)) = :prefix
+ORDER BY
+    created_at DESC,
+    id DESC
+"#;
+        let mut stmt = self.connection.prepare(query)?;
+        let rows = stmt.query_map(&[(":prefix", &prefix)], TrashEntry::from_row)?;
+        rows.collect()
+    }
+
+    /// Returns every entry whose payload lives somewhere under `dir`.
+    pub(crate) fn find_by_trash_dir(&self, dir: &Path) -> Result<Vec<TrashEntry>, rusqlite::Error> {
+        let query = r#"
+SELECT
+    id,
+    original_path,
+    trash_path,
+    is_dir,
+    link_target,
+    file_size,
+    blake3sum,
+    mtime,
+    atime,
+    unix_mode,
+    uid,
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
+FROM
+    trash_entry
+WHERE
+    substr(trash_path, 1, length(:prefix)) = :prefix
+"#;
+        let mut prefix = dir.to_string_lossy().to_string();
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let mut stmt = self.connection.prepare(query)?;
+        let rows = stmt.query_map(&[(":prefix", &prefix)], TrashEntry::from_row)?;
+        rows.collect()
+    }
+
     pub(crate) fn find_by_id(&self, id: i64) -> Result<Option<TrashEntry>, rusqlite::Error> {
         let query = r#"
 SELECT
@@ -229,31 +650,20 @@ SELECT
     atime,
     unix_mode,
     uid,
-    gid
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
 FROM
     trash_entry
 WHERE
     id = :id
 "#;
         let mut stmt = self.connection.prepare(query)?;
-        let mut r = stmt.query_map(&[(":id", &id)], |row| {
-            Ok(TrashEntry {
-                id: row.get("id")?,
-                metadata: FileMetadata {
-                    original_path: row.get("original_path")?,
-                    file_size: row.get("file_size")?,
-                    is_dir: row.get("is_dir")?,
-                    link_target: row.get("link_target")?,
-                    blake3sum: row.get("blake3sum")?,
-                    mtime: row.get("mtime")?,
-                    atime: row.get("atime")?,
-                    unix_mode: row.get("unix_mode")?,
-                    uid: row.get("uid")?,
-                    gid: row.get("gid")?,
-                },
-                trash_path: row.get::<_, String>("trash_path")?.into(),
-            })
-        })?;
+        let mut r = stmt.query_map(&[(":id", &id)], TrashEntry::from_row)?;
         match r.next() {
             Some(Ok(meta)) => Ok(Some(meta)),
             Some(Err(e)) => Err(e),
@@ -275,33 +685,23 @@ SELECT
     atime,
     unix_mode,
     uid,
-    gid
+    gid,
+    created_at,
+    expiration,
+    compression,
+    stored_size,
+    encrypted,
+    pinned
 FROM
     trash_entry
 WHERE
     expiration < :now
+    AND NOT pinned
 ORDER BY
-    abspath DESC
+    trash_path DESC
         "#;
         let mut stmt = self.connection.prepare(query)?;
-        let rows = stmt.query_map(&[(":now", &now)], |row| {
-            Ok(TrashEntry {
-                id: row.get("id")?,
-                metadata: FileMetadata {
-                    original_path: row.get("original_path")?,
-                    file_size: row.get("file_size")?,
-                    is_dir: row.get("is_dir")?,
-                    link_target: row.get("link_target")?,
-                    blake3sum: row.get("blake3sum")?,
-                    mtime: row.get("mtime")?,
-                    atime: row.get("atime")?,
-                    unix_mode: row.get("unix_mode")?,
-                    uid: row.get("uid")?,
-                    gid: row.get("gid")?,
-                },
-                trash_path: row.get::<_, String>("trash_path")?.into(),
-            })
-        })?;
+        let rows = stmt.query_map(&[(":now", &now)], TrashEntry::from_row)?;
         let mut results = Vec::new();
         for row in rows {
             results.push(row?);
@@ -317,10 +717,8 @@ mod test {
 
     fn setup() -> MetadataDB {
         let connection = Connection::open_in_memory().unwrap();
-        let sql_string = std::include_str!("metadata_db.sql");
-        connection.execute_batch(sql_string).unwrap();
-        let config = Rc::new(Config::default());
-        MetadataDB { connection, config }
+        migrate(&connection).unwrap();
+        MetadataDB { connection }
     }
 
     #[test]
@@ -339,7 +737,16 @@ mod test {
             gid: 1000,
         };
         let generated_path = PathBuf::from("/tmp/Some/Generated/Path");
-        let entry = suite.create(meta.clone(), &generated_path).unwrap();
+        let entry = suite
+            .create(
+                meta.clone(),
+                &generated_path,
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
         assert_eq!(entry.id, 1);
         assert_eq!(meta.original_path, entry.metadata.original_path);
         assert_eq!(meta.file_size, entry.metadata.file_size);
@@ -367,7 +774,16 @@ mod test {
             gid: 1000,
         };
         let generated_path = PathBuf::from("/tmp/Some/Generated/Path");
-        let entry = suite.create(meta.clone(), &generated_path).unwrap();
+        let entry = suite
+            .create(
+                meta.clone(),
+                &generated_path,
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
         suite.delete(entry.id).unwrap();
         let result = suite.find_by_id(entry.id).unwrap();
         assert!(result.is_none());
@@ -389,7 +805,16 @@ mod test {
             gid: 1000,
         };
         let generated_path = PathBuf::from("/tmp/a.txt");
-        let entry = suite.create(meta.clone(), &generated_path).unwrap();
+        let entry = suite
+            .create(
+                meta.clone(),
+                &generated_path,
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
         let meta_found = suite.find_by_id(entry.id).unwrap().unwrap();
         assert_eq!(meta.file_size, meta_found.metadata.file_size);
         assert_eq!(meta.blake3sum, meta_found.metadata.blake3sum);
@@ -400,4 +825,266 @@ mod test {
         assert_eq!(meta.gid, meta_found.metadata.gid);
         assert_eq!(meta.original_path, meta_found.metadata.original_path);
     }
+
+    #[test]
+    fn test_list() {
+        let suite = setup();
+        let files = [
+            ("/home/me/a.txt", 30, 1000, false),
+            ("/home/me/dir", 20, 2000, true),
+            ("/srv/b.txt", 10, 3000, false),
+        ];
+        for (i, (path, size, created_at, is_dir)) in files.iter().enumerate() {
+            let meta = FileMetadata {
+                original_path: path.to_string(),
+                file_size: *size,
+                is_dir: *is_dir,
+                link_target: None,
+                blake3sum: format!("hash{}", i),
+                mtime: 0,
+                atime: 0,
+                unix_mode: 0o644,
+                uid: 1000,
+                gid: 1000,
+            };
+            let generated_path = PathBuf::from(format!("/trash/{}", i));
+            suite
+                .create_at(meta, &generated_path, Stored::default(), *created_at, 60)
+                .unwrap();
+        }
+        let paths = |filter: ListFilter| -> Vec<String> {
+            let entries = suite.list(&filter).unwrap();
+            entries
+                .into_iter()
+                .map(|e| e.metadata.original_path)
+                .collect()
+        };
+        assert_eq!(
+            paths(ListFilter::default()),
+            vec!["/srv/b.txt", "/home/me/dir", "/home/me/a.txt"]
+        );
+        assert_eq!(
+            paths(ListFilter {
+                sort: SortKey::Size,
+                limit: Some(2),
+                ..Default::default()
+            }),
+            vec!["/home/me/a.txt", "/home/me/dir"]
+        );
+        assert_eq!(
+            paths(ListFilter {
+                path_prefix: Some("/home/".to_string()),
+                since: Some(1500),
+                ..Default::default()
+            }),
+            vec!["/home/me/dir"]
+        );
+        assert_eq!(
+            paths(ListFilter {
+                path_prefix: Some("/home/me/dir".to_string()),
+                ..Default::default()
+            }),
+            vec!["/home/me/dir"]
+        );
+        assert!(paths(ListFilter {
+            path_prefix: Some("/home/m".to_string()),
+            ..Default::default()
+        })
+        .is_empty());
+        assert_eq!(
+            paths(ListFilter {
+                path_prefix: Some("/".to_string()),
+                ..Default::default()
+            })
+            .len(),
+            3
+        );
+        assert_eq!(
+            paths(ListFilter {
+                dirs_only: true,
+                ..Default::default()
+            }),
+            vec!["/home/me/dir"]
+        );
+        assert_eq!(
+            paths(ListFilter {
+                until: Some(2000),
+                sort: SortKey::Path,
+                reverse: true,
+                ..Default::default()
+            }),
+            vec!["/home/me/dir", "/home/me/a.txt"]
+        );
+
+        let operation = suite.create_operation("make clean").unwrap();
+        let meta = FileMetadata {
+            original_path: "/srv/build/main.o".to_string(),
+            file_size: 5,
+            is_dir: false,
+            link_target: None,
+            blake3sum: "hash3".to_string(),
+            mtime: 0,
+            atime: 0,
+            unix_mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+        };
+        suite
+            .create(
+                meta,
+                Path::new("/trash/3"),
+                Stored::default(),
+                60,
+                false,
+                Some(operation),
+            )
+            .unwrap();
+        assert_eq!(
+            paths(ListFilter {
+                operation: Some(operation),
+                ..Default::default()
+            }),
+            vec!["/srv/build/main.o"]
+        );
+    }
+
+    #[test]
+    fn test_find_by_path_and_hash_prefix() {
+        let suite = setup();
+        for (path, hash) in [("/tmp/a", "abcdef0123"), ("/tmp/b", "abcdef9999")] {
+            let meta = FileMetadata {
+                original_path: path.to_string(),
+                file_size: 1,
+                is_dir: false,
+                link_target: None,
+                blake3sum: hash.to_string(),
+                mtime: 0,
+                atime: 0,
+                unix_mode: 0o644,
+                uid: 1000,
+                gid: 1000,
+            };
+            suite
+                .create(
+                    meta,
+                    &PathBuf::from(format!("/trash{}", path)),
+                    Stored::default(),
+                    60,
+                    false,
+                    None,
+                )
+                .unwrap();
+        }
+        let found = suite.find(Path::new("/tmp/b")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].metadata.blake3sum, "abcdef9999");
+
+        assert_eq!(suite.find_by_hash_prefix("abcd").unwrap().len(), 2);
+        let found = suite.find_by_hash_prefix("abcdef01").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].metadata.original_path, "/tmp/a");
+        assert!(suite.find_by_hash_prefix("ffff").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_duplicates() {
+        let suite = setup();
+        let meta = FileMetadata {
+            original_path: "/tmp/config.yaml".to_string(),
+            file_size: 4,
+            is_dir: false,
+            link_target: None,
+            blake3sum: "cafebabe".to_string(),
+            mtime: 0,
+            atime: 0,
+            unix_mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+        };
+        let first = suite
+            .create(
+                meta.clone(),
+                Path::new("/trash/a"),
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
+        let second = suite
+            .create(
+                meta,
+                Path::new("/trash/a"),
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(suite.refcount(Path::new("/trash/a")).unwrap(), 2);
+        let history = suite
+            .list(&ListFilter {
+                original_path: Some("/tmp/config.yaml".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].id, second.id);
+
+        assert_eq!(suite.delete(first.id).unwrap(), 1);
+        assert_eq!(suite.delete(second.id).unwrap(), 0);
+        assert_eq!(suite.refcount(Path::new("/trash/a")).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_migrate_legacy_schema() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                r#"
+CREATE TABLE trash_entry (
+    id INTEGER PRIMARY KEY,
+    created_at INTEGER DEFAULT (unixepoch()),
+    expiration INTEGER NOT NULL CHECK (expiration > created_at),
+    blake3sum TEXT NOT NULL,
+    original_path TEXT NOT NULL,
+    trash_path TEXT NOT NULL,
+    is_dir BOOL NOT NULL DEFAULT FALSE,
+    is_link BOOL GENERATED ALWAYS AS (link_target IS NOT NULL) VIRTUAL,
+    link_target TEXT DEFAULT NULL,
+    file_size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    atime INTEGER NOT NULL,
+    unix_mode INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL,
+    UNIQUE (blake3sum),
+    UNIQUE (original_path),
+    UNIQUE (expiration)
+);
+CREATE INDEX file_hash_slug_idx ON trash_entry(substr(blake3sum, 1, 7));
+INSERT INTO trash_entry (expiration, blake3sum, original_path, trash_path, file_size, mtime, atime, unix_mode, uid, gid)
+VALUES (unixepoch() + 60, 'cafebabe', '/tmp/a', '/trash/a', 1, 0, 0, 420, 1000, 1000);
+"#,
+            )
+            .unwrap();
+        migrate(&connection).unwrap();
+        migrate(&connection).unwrap();
+        let suite = MetadataDB { connection };
+        assert_eq!(suite.find(Path::new("/tmp/a")).unwrap().len(), 1);
+        let meta = suite.find_by_id(1).unwrap().unwrap().metadata;
+        suite
+            .create(
+                meta,
+                Path::new("/trash/a2"),
+                Stored::default(),
+                60,
+                false,
+                None,
+            )
+            .unwrap();
+        assert_eq!(suite.find(Path::new("/tmp/a")).unwrap().len(), 2);
+        assert_eq!(suite.refcount(Path::new("/trash/a")).unwrap(), 1);
+    }
 }
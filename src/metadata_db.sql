@@ -15,8 +15,7 @@ CREATE TABLE IF NOT EXISTS trash_entry (
     uid INTEGER NOT NULL,
     gid INTEGER NOT NULL,
     UNIQUE (blake3sum),
-    UNIQUE (original_path),
-    UNIQUE (expiration)
+    UNIQUE (original_path)
 );
 
-CREATE INDEX file_hash_slug_idx ON trash_entry(substr(blake3sum, 1, 7));
+CREATE INDEX IF NOT EXISTS file_hash_slug_idx ON trash_entry(substr(blake3sum, 1, 7));
@@ -0,0 +1,38 @@
+-- Every deletion gets its own row, so the same path (or the same content)
+-- can be in the trash several times. Drops the UNIQUE constraints on
+-- blake3sum, original_path and expiration.
+CREATE TABLE trash_entry_new (
+    id INTEGER PRIMARY KEY,
+    created_at INTEGER DEFAULT (unixepoch()),
+    expiration INTEGER NOT NULL CHECK (expiration > created_at),
+    blake3sum TEXT NOT NULL,
+    original_path TEXT NOT NULL,
+    trash_path TEXT NOT NULL,
+    is_dir BOOL NOT NULL DEFAULT FALSE,
+    is_link BOOL GENERATED ALWAYS AS (link_target IS NOT NULL) VIRTUAL,
+    link_target TEXT DEFAULT NULL,
+    file_size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    atime INTEGER NOT NULL,
+    unix_mode INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    gid INTEGER NOT NULL
+);
+
+INSERT INTO trash_entry_new (
+    id, created_at, expiration, blake3sum, original_path, trash_path, is_dir,
+    link_target, file_size, mtime, atime, unix_mode, uid, gid
+)
+SELECT
+    id, created_at, expiration, blake3sum, original_path, trash_path, is_dir,
+    link_target, file_size, mtime, atime, unix_mode, uid, gid
+FROM
+    trash_entry;
+
+DROP TABLE trash_entry;
+
+ALTER TABLE trash_entry_new RENAME TO trash_entry;
+
+CREATE INDEX file_hash_slug_idx ON trash_entry(substr(blake3sum, 1, 7));
+
+CREATE INDEX original_path_idx ON trash_entry(original_path, created_at);
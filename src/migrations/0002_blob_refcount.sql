@@ -0,0 +1,18 @@
+-- Payloads in the trash can be shared by several entries (identical files
+-- are stored once), so each one is reference counted and only removed from
+-- disk when the last entry pointing at it goes away.
+CREATE TABLE blob (
+    path TEXT PRIMARY KEY,
+    refcount INTEGER NOT NULL CHECK (refcount >= 0)
+);
+
+INSERT INTO blob (path, refcount)
+SELECT
+    trash_path,
+    COUNT(*)
+FROM
+    trash_entry
+GROUP BY
+    trash_path;
+
+CREATE INDEX trash_path_idx ON trash_entry(trash_path);
@@ -0,0 +1,6 @@
+-- Payloads may be stored compressed. Record the codec each one was written
+-- with, and its size on disk next to the logical file_size; existing
+-- payloads are uncompressed.
+ALTER TABLE trash_entry ADD COLUMN compression TEXT NOT NULL DEFAULT 'none';
+ALTER TABLE trash_entry ADD COLUMN stored_size INTEGER NOT NULL DEFAULT 0;
+UPDATE trash_entry SET stored_size = file_size;
@@ -0,0 +1,2 @@
+-- Payloads may be encrypted to the key in the trash directory's keyring.
+ALTER TABLE trash_entry ADD COLUMN encrypted BOOL NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Pinned entries stay in the trash until they are unpinned.
+ALTER TABLE trash_entry ADD COLUMN pinned BOOL NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- Deletions can be grouped into an operation, e.g. everything one command
+-- run under rim-wrap deleted, so they can be recovered together.
+CREATE TABLE operation (
+    id INTEGER PRIMARY KEY,
+    created_at INTEGER DEFAULT (unixepoch()),
+    command TEXT NOT NULL
+);
+
+ALTER TABLE trash_entry ADD COLUMN operation INTEGER DEFAULT NULL REFERENCES operation(id);
+
+CREATE INDEX operation_idx ON trash_entry(operation);
@@ -0,0 +1,181 @@
+//! Machine-readable output shared by the command line tools.
+//!
+//! Schema version 1. Every JSON document and every NDJSON line carries a
+//! `schema_version` field. A trash entry is an object with the fields
+//! `id`, `original_path`, `trash_path`, `is_dir`, `link_target` (string or
+//! null), `file_size`, `blake3sum`, `mtime`, `atime`, `unix_mode`, `uid`,
+//! `gid`, `created_at`, `expiration`, `compression` (`none`, `zstd` or
+//! `gzip`), `stored_size`, `encrypted` and `pinned`; times are seconds since
+//! the Unix epoch, and `stored_size` is the size of the payload on disk.
+//! With `json` a command prints a single object holding its entries in an
+//! array (e.g. `{"schema_version": 1, "entries": [...]}`); with
+//! `ndjson` it prints one entry per line; with `tsv` it prints a header row
+//! followed by one row per entry, in the field order above. New fields may
+//! be added within a schema version; renaming or removing a field bumps
+//! [`SCHEMA_VERSION`].
+
+use crate::metadata_db::TrashEntry;
+use serde::Serialize;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Aligned columns for humans
+    #[default]
+    Table,
+    Json,
+    Ndjson,
+    Tsv,
+}
+
+const TSV_HEADER: &str = "id\toriginal_path\ttrash_path\tis_dir\tlink_target\tfile_size\tblake3sum\tmtime\tatime\tunix_mode\tuid\tgid\tcreated_at\texpiration\tcompression\tstored_size\tencrypted\tpinned";
+
+#[derive(Serialize)]
+struct Versioned<T: Serialize> {
+    schema_version: u32,
+    #[serde(flatten)]
+    inner: T,
+}
+
+/// Prints `entries` in one of the machine-readable formats, using `key` as
+/// the name of the array in `json` output. Does nothing for
+/// [`Format::Table`], which every command renders itself.
+pub fn print_entries(format: Format, key: &str, entries: &[TrashEntry]) {
+    match format {
+        Format::Table => (),
+        Format::Json => {
+            let mut document = serde_json::Map::new();
+            document.insert(key.to_string(), serde_json::to_value(entries).unwrap());
+            print_json_object(document);
+        }
+        Format::Ndjson => {
+            for entry in entries {
+                let line = Versioned {
+                    schema_version: SCHEMA_VERSION,
+                    inner: entry,
+                };
+                println!("{}", serde_json::to_string(&line).unwrap());
+            }
+        }
+        Format::Tsv => {
+            println!("{}", TSV_HEADER);
+            for entry in entries {
+                println!("{}", tsv_row(entry));
+            }
+        }
+    }
+}
+
+/// Prints a single JSON object with `schema_version` added to it.
+pub fn print_json_object(object: serde_json::Map<String, serde_json::Value>) {
+    let document = Versioned {
+        schema_version: SCHEMA_VERSION,
+        inner: object,
+    };
+    println!("{}", serde_json::to_string_pretty(&document).unwrap());
+}
+
+fn tsv_row(entry: &TrashEntry) -> String {
+    let meta = &entry.metadata;
+    [
+        entry.id.to_string(),
+        tsv_escape(&meta.original_path),
+        tsv_escape(&entry.trash_path.to_string_lossy()),
+        meta.is_dir.to_string(),
+        meta.link_target
+            .as_deref()
+            .map(tsv_escape)
+            .unwrap_or_default(),
+        meta.file_size.to_string(),
+        meta.blake3sum.clone(),
+        meta.mtime.to_string(),
+        meta.atime.to_string(),
+        meta.unix_mode.to_string(),
+        meta.uid.to_string(),
+        meta.gid.to_string(),
+        entry.created_at.to_string(),
+        entry.expiration.to_string(),
+        entry.stored.compression.to_string(),
+        entry.stored.stored_size.to_string(),
+        entry.stored.encrypted.to_string(),
+        entry.pinned.to_string(),
+    ]
+    .join("\t")
+}
+
+/// Escapes the characters which would break a TSV row.
+fn tsv_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compression::Codec;
+    use crate::fs::FileMetadata;
+    use crate::metadata_db::Stored;
+    use std::path::PathBuf;
+
+    fn entry() -> TrashEntry {
+        TrashEntry {
+            id: 7,
+            metadata: FileMetadata {
+                original_path: "/home/me/tab\there".to_string(),
+                is_dir: false,
+                link_target: None,
+                file_size: 12,
+                blake3sum: "cafebabe".to_string(),
+                mtime: 1,
+                atime: 2,
+                unix_mode: 0o100644,
+                uid: 1000,
+                gid: 100,
+            },
+            trash_path: PathBuf::from("/trash/tab_cafebab"),
+            created_at: 10,
+            expiration: 20,
+            stored: Stored {
+                compression: Codec::Zstd,
+                stored_size: 5,
+                encrypted: false,
+            },
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn test_entry_fields() {
+        let value = serde_json::to_value(Versioned {
+            schema_version: SCHEMA_VERSION,
+            inner: &entry(),
+        })
+        .unwrap();
+        let mut keys: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        let mut expected: Vec<&str> = TSV_HEADER.split('\t').collect();
+        expected.push("schema_version");
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(value["original_path"], "/home/me/tab\there");
+        assert_eq!(value["link_target"], serde_json::Value::Null);
+        assert_eq!(value["compression"], "zstd");
+    }
+
+    #[test]
+    fn test_tsv_row() {
+        let row = tsv_row(&entry());
+        let fields: Vec<&str> = row.split('\t').collect();
+        assert_eq!(fields.len(), TSV_HEADER.split('\t').count());
+        assert_eq!(fields[1], "/home/me/tab\\there");
+        assert_eq!(fields[4], "");
+    }
+}
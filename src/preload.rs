@@ -0,0 +1,150 @@
+//! The LD_PRELOAD library which sends other programs' deletions to rim
+//! (built from `src/interceptor.c`), and the policy deciding which of them
+//! rim takes.
+
+use crate::config::Config;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+#[cfg(rim_preload)]
+const LIBRARY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/librim_preload.so"));
+
+/// Exit status of `rim intercept` which tells the library to go ahead with
+/// the real call
+pub const PASS: i32 = 125;
+
+/// Returns the path of the library, writing it out to the user's cache
+/// directory the first time. The file name carries a hash of the contents,
+/// so every build of rim gets its own copy.
+#[cfg(rim_preload)]
+pub fn library_path() -> Result<PathBuf, std::io::Error> {
+    let dir = dirs_next::cache_dir().unwrap_or_else(std::env::temp_dir);
+    install(&dir.join("rim"))
+}
+
+#[cfg(rim_preload)]
+fn install(dir: &Path) -> Result<PathBuf, std::io::Error> {
+    let hash = blake3::hash(LIBRARY).to_hex();
+    let path = dir.join(format!("librim_preload-{}.so", &hash[..16]));
+    if path.exists() {
+        return Ok(path);
+    }
+    std::fs::create_dir_all(dir)?;
+    let partial = dir.join(format!(".librim_preload.{}.part", std::process::id()));
+    let mut file = std::fs::File::create(&partial)?;
+    file.write_all(LIBRARY)?;
+    file.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+    file.sync_all()?;
+    std::fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+#[cfg(not(rim_preload))]
+pub fn library_path() -> Result<PathBuf, std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "The LD_PRELOAD library is only available on Linux",
+    ))
+}
+
+/// Whether intercepted deletions of `path` should really happen instead of
+/// going to the trash: the trash itself, pseudo file systems and whatever
+/// `preload_exclude` lists. `path` must have gone through
+/// [`crate::fs::resolve_parent`], so that neither `..` nor a symbolic link
+/// to a directory can make it look like it is elsewhere; the directories it
+/// is checked against are resolved as well.
+pub fn excluded(config: &Config, path: &Path) -> bool {
+    let builtin = [Path::new("/proc"), Path::new("/sys"), Path::new("/dev")];
+    let trashes = [Some(config.trashdir.clone()), config.freedesktop_trashdir()];
+    let under = |dir: &Path| {
+        path.starts_with(dir) || dir.canonicalize().is_ok_and(|dir| path.starts_with(dir))
+    };
+    builtin.iter().any(|dir| path.starts_with(dir))
+        || trashes.iter().flatten().any(|dir| under(dir))
+        || config.preload_exclude.iter().any(|dir| under(dir))
+        // per-mount trash directories
+        || path.components().any(|c| {
+            c.as_os_str()
+                .to_string_lossy()
+                .starts_with(".rim-trash-")
+        })
+}
+
+/// The exit status `rim intercept` reports `e` with: the errno the
+/// intercepted call should fail with.
+pub fn exit_status(e: &std::io::Error) -> i32 {
+    let errno = e.raw_os_error().unwrap_or(match e.kind() {
+        std::io::ErrorKind::NotFound => libc::ENOENT,
+        std::io::ErrorKind::PermissionDenied => libc::EACCES,
+        std::io::ErrorKind::IsADirectory => libc::EISDIR,
+        std::io::ErrorKind::NotADirectory => libc::ENOTDIR,
+        std::io::ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        std::io::ErrorKind::StorageFull => libc::ENOSPC,
+        _ => libc::EIO,
+    });
+    // keep clear of PASS and the statuses the shell uses
+    if (1..PASS).contains(&errno) {
+        errno
+    } else {
+        libc::EIO
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_excluded() {
+        let config = Config {
+            trashdir: PathBuf::from("/home/me/.rim-trash"),
+            preload_exclude: vec![PathBuf::from("/tmp")],
+            ..Default::default()
+        };
+        assert!(excluded(&config, Path::new("/tmp/cc1234.o")));
+        assert!(excluded(&config, Path::new("/proc/self/fd/3")));
+        assert!(excluded(&config, Path::new("/home/me/.rim-trash/rim.db")));
+        assert!(excluded(&config, Path::new("/mnt/usb/.rim-trash-1000/x")));
+        assert!(!excluded(&config, Path::new("/tmpfile")));
+        assert!(!excluded(&config, Path::new("/home/me/notes.txt")));
+
+        // excluded directories count under their real name too
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+        let config = Config {
+            preload_exclude: vec![dir.path().join("link")],
+            ..Default::default()
+        };
+        let resolved = |p: &str| crate::fs::resolve_parent(&dir.path().join(p)).unwrap();
+        assert!(excluded(&config, &resolved("real/a.o")));
+        assert!(excluded(&config, &resolved("link/a.o")));
+        let config = Config {
+            preload_exclude: vec![real.join("tmp")],
+            ..Default::default()
+        };
+        std::fs::create_dir_all(real.join("tmp/sub")).unwrap();
+        std::fs::create_dir_all(real.join("home")).unwrap();
+        assert!(!excluded(
+            &config,
+            &resolved("real/tmp/sub/../../home/b.txt")
+        ));
+        assert!(excluded(&config, &resolved("real/home/../tmp/b.txt")));
+
+        let missing = std::io::Error::from_raw_os_error(libc::ENOENT);
+        assert_eq!(exit_status(&missing), libc::ENOENT);
+        let full = std::io::Error::new(std::io::ErrorKind::StorageFull, "full");
+        assert_eq!(exit_status(&full), libc::ENOSPC);
+    }
+
+    #[cfg(rim_preload)]
+    #[test]
+    fn test_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = install(&dir.path().join("rim")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), LIBRARY);
+        assert_eq!(install(&dir.path().join("rim")).unwrap(), path);
+    }
+}
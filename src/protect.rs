@@ -0,0 +1,269 @@
+//! Paths rim refuses to recycle unless told otherwise
+
+use crate::config::Config;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Name of the file which protects the directory it's in
+pub const MARKER: &str = ".rim-protect";
+
+/// The error (inside an `std::io::Error` of kind `PermissionDenied`) for a
+/// path which is protected
+#[derive(Debug)]
+pub struct Protected {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Protected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' is protected: {}", self.path.display(), self.reason)
+    }
+}
+
+impl std::error::Error for Protected {}
+
+/// Fails if recycling `path` would take something protected with it: the
+/// root directory, the home directory, a mount point, the trash itself, a
+/// path listed in the `protect` setting, or a directory with a
+/// `.rim-protect` file in it. Symbolic links are only protected if listed
+/// themselves.
+pub fn check(config: &Config, path: &Path) -> Result<(), std::io::Error> {
+    let path = crate::fs::resolve_parent(path)?;
+    let metadata = path.symlink_metadata()?;
+    let reason = if metadata.is_dir() {
+        dir_reason(config, &path, &metadata)?
+    } else {
+        trash_reason(config, &path).or_else(|| listed_reason(config, &path))
+    };
+    refuse(path, reason)
+}
+
+/// The part of [`check`] which holds even for protected paths the user
+/// insists on: the trash can't be recycled into itself, neither whole nor
+/// in part.
+pub fn check_trash(config: &Config, path: &Path) -> Result<(), std::io::Error> {
+    let path = crate::fs::resolve_parent(path)?;
+    let reason = trash_reason(config, &path);
+    refuse(path, reason)
+}
+
+fn refuse(path: PathBuf, reason: Option<String>) -> Result<(), std::io::Error> {
+    match reason {
+        Some(reason) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            Protected { path, reason },
+        )),
+        None => Ok(()),
+    }
+}
+
+fn dir_reason(
+    config: &Config,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<Option<String>, std::io::Error> {
+    if path.parent().is_none() {
+        return Ok(Some("it's the root directory".to_string()));
+    }
+    for home in dirs_next::home_dir().iter().flat_map(|home| resolved(home)) {
+        if home == path {
+            return Ok(Some("it's the home directory".to_string()));
+        }
+        if home.starts_with(path) {
+            return Ok(Some(format!(
+                "it contains the home directory, {}",
+                home.display()
+            )));
+        }
+    }
+    if let Some(reason) = trash_reason(config, path) {
+        return Ok(Some(reason));
+    }
+    if let Some(reason) = listed_reason(config, path) {
+        return Ok(Some(reason));
+    }
+    if path.parent().unwrap().metadata()?.dev() != metadata.dev() {
+        return Ok(Some("it's a mount point".to_string()));
+    }
+    if path.join(MARKER).symlink_metadata().is_ok() {
+        return Ok(Some(format!("it has a {} file", MARKER)));
+    }
+    // whatever is protected further down goes too
+    let dev = metadata.dev();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() {
+                continue;
+            }
+            let subdir = entry.path();
+            if metadata.dev() != dev {
+                return Ok(Some(format!(
+                    "it contains the mount point {}",
+                    subdir.display()
+                )));
+            }
+            if is_per_mount_trash(&subdir) {
+                return Ok(Some(format!(
+                    "it contains the per-mount trash {}",
+                    subdir.display()
+                )));
+            }
+            if subdir.join(MARKER).symlink_metadata().is_ok() {
+                return Ok(Some(format!(
+                    "it contains {}, which has a {} file",
+                    subdir.display(),
+                    MARKER
+                )));
+            }
+            pending.push(subdir);
+        }
+    }
+    Ok(None)
+}
+
+/// Why recycling `path` would take (part of) the trash with it: the
+/// configured one, the FreeDesktop.org one or a per-mount one
+fn trash_reason(config: &Config, path: &Path) -> Option<String> {
+    let trashes = [Some(config.trashdir.clone()), config.freedesktop_trashdir()];
+    for trash in trashes.iter().flatten().flat_map(|trash| resolved(trash)) {
+        if trash == path {
+            return Some("it's the trash".to_string());
+        }
+        if path.starts_with(&trash) {
+            return Some(format!("it's in the trash, {}", trash.display()));
+        }
+        if trash.starts_with(path) {
+            return Some(format!("it contains the trash, {}", trash.display()));
+        }
+    }
+    if path.ancestors().any(is_per_mount_trash) {
+        return Some("it's in a per-mount trash".to_string());
+    }
+    let contains = std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .find(|entry| is_per_mount_trash(&entry.path()));
+    contains.map(|entry| format!("it contains the per-mount trash {}", entry.path().display()))
+}
+
+/// Whether `path` is named like a `.rim-trash-$UID` directory
+fn is_per_mount_trash(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with(".rim-trash-"))
+}
+
+fn listed_reason(config: &Config, path: &Path) -> Option<String> {
+    let listed = config
+        .protect
+        .iter()
+        .flat_map(|listed| resolved(&crate::config::expand_home(listed)));
+    for listed in listed {
+        if listed == path {
+            return Some("it's listed in protect".to_string());
+        }
+        if listed.starts_with(path) {
+            return Some(format!(
+                "it contains {}, which is listed in protect",
+                listed.display()
+            ));
+        }
+    }
+    None
+}
+
+/// What `path` is compared with, as the path checked is resolved too: `path`
+/// with symbolic links resolved in its parent, which protects a listed
+/// symbolic link itself, and with all of them resolved, which protects what
+/// it leads to. Either is left out if the path doesn't exist.
+fn resolved(path: &Path) -> Vec<PathBuf> {
+    [crate::fs::resolve_parent(path), path.canonicalize()]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("projects");
+        std::fs::create_dir_all(dir.join("thesis/chapters")).unwrap();
+        std::fs::create_dir_all(dir.join("scratch")).unwrap();
+        std::fs::write(dir.join("scratch/notes.txt"), "").unwrap();
+        std::fs::write(dir.join("thesis").join(MARKER), "").unwrap();
+        let config = Config {
+            protect: vec![dir.join("keys")],
+            ..Default::default()
+        };
+        std::fs::create_dir(dir.join("keys")).unwrap();
+
+        let reason = |path: &Path| -> Option<String> {
+            match check(&config, path) {
+                Ok(()) => None,
+                Err(e) => Some(e.into_inner().unwrap().to_string()),
+            }
+        };
+        assert_eq!(reason(&dir.join("scratch")), None);
+        assert_eq!(reason(&dir.join("scratch/notes.txt")), None);
+        assert_eq!(reason(&dir.join("thesis/chapters")), None);
+        assert!(reason(&dir.join("thesis"))
+            .unwrap()
+            .ends_with("has a .rim-protect file"));
+        assert!(reason(&dir.join("keys/../keys"))
+            .unwrap()
+            .ends_with("it's listed in protect"));
+        // either the marker or the listed path below gets it refused
+        assert!(reason(&dir).unwrap().contains("it contains"));
+        assert!(reason(Path::new("/"))
+            .unwrap()
+            .ends_with("it's the root directory"));
+
+        // listed under another name, as with /home leading to /var/home
+        let var = root.path().join("var/home/me");
+        std::fs::create_dir_all(var.join("Documents/taxes")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("var/home"), root.path().join("home")).unwrap();
+        let config = Config {
+            protect: vec![root.path().join("home/me/Documents")],
+            ..Default::default()
+        };
+        let reason = |path: &Path| check(&config, path).map_err(|e| e.to_string());
+        assert!(reason(&var.join("Documents"))
+            .unwrap_err()
+            .ends_with("it's listed in protect"));
+        assert!(reason(&var)
+            .unwrap_err()
+            .contains("which is listed in protect"));
+        assert!(reason(&var.join("Documents/taxes")).is_ok());
+
+        // nor can the trash go, nor what's in it, nor what holds it
+        let trash = root.path().join("var/rim");
+        std::fs::create_dir_all(&trash).unwrap();
+        std::fs::write(trash.join("rim.db"), "").unwrap();
+        let per_mount = root.path().join("mnt/.rim-trash-1000");
+        std::fs::create_dir_all(per_mount.join("objects")).unwrap();
+        let config = Config {
+            // home leads to var/home, so this is var/rim
+            trashdir: root.path().join("home/../rim"),
+            ..Default::default()
+        };
+        let reason = |path: &Path| check(&config, path).unwrap_err().to_string();
+        assert!(reason(&trash).ends_with("it's the trash"));
+        assert!(reason(&trash.join("rim.db")).contains("it's in the trash"));
+        assert!(reason(&root.path().join("var")).contains("it contains the trash"));
+        assert!(reason(&per_mount.join("objects")).ends_with("in a per-mount trash"));
+        assert!(reason(&root.path().join("mnt")).contains("it contains the per-mount trash"));
+        assert!(reason(root.path()).contains("it contains"));
+        // which even --allow-protected doesn't change
+        assert!(check_trash(&config, &trash.join("rim.db")).is_err());
+        assert!(check_trash(&config, &root.path().join("var")).is_err());
+        assert!(check_trash(&config, &var).is_ok());
+    }
+}
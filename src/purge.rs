@@ -0,0 +1,228 @@
+//! Permanently destroying payloads once they leave the trash for good
+
+use serde::{Deserialize, Serialize};
+use std::io::{Seek, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// The `purge_mode` setting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurgeMode {
+    /// Just unlink, leaving the data on disk until it's reused
+    #[default]
+    Unlink,
+    /// Overwrite the contents once (zeros unless configured otherwise)
+    Overwrite,
+    /// Overwrite the contents several times (random data unless configured
+    /// otherwise), like shred(1)
+    Shred,
+}
+
+/// What is written over a file's contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    Zero,
+    Random,
+}
+
+/// How to destroy payloads, with the defaults of the configured mode filled
+/// in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Purge {
+    pub mode: PurgeMode,
+    pub passes: u32,
+    pub pattern: Pattern,
+}
+
+impl Purge {
+    pub fn from_config(config: &crate::config::Config) -> Purge {
+        let (passes, pattern) = match config.purge_mode {
+            PurgeMode::Unlink => (0, Pattern::Zero),
+            PurgeMode::Overwrite => (1, Pattern::Zero),
+            PurgeMode::Shred => (3, Pattern::Random),
+        };
+        Purge {
+            mode: config.purge_mode,
+            passes: match config.purge_mode {
+                PurgeMode::Unlink => 0,
+                _ => config.purge_passes.unwrap_or(passes),
+            },
+            pattern: config.purge_pattern.unwrap_or(pattern),
+        }
+    }
+}
+
+/// Removes a payload from the trash, overwriting the contents of its
+/// regular files first unless `purge` says to just unlink. Returns warnings
+/// about data that overwriting may not have reached.
+pub fn purge(path: &Path, purge: &Purge) -> Result<Vec<String>, std::io::Error> {
+    let mut warnings = vec![];
+    if purge.mode != PurgeMode::Unlink {
+        match overwrite_tree(path, purge, &mut warnings) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(warnings),
+            result => result?,
+        }
+    }
+    remove(path)?;
+    Ok(warnings)
+}
+
+/// Removes a payload from the trash, whether it's a file, an archive or a
+/// directory stored as-is. A payload which is already gone is not an error.
+pub fn remove(path: &Path) -> Result<(), std::io::Error> {
+    let removed = match path.symlink_metadata() {
+        Ok(m) if m.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match removed {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn overwrite_tree(
+    path: &Path,
+    purge: &Purge,
+    warnings: &mut Vec<String>,
+) -> Result<(), std::io::Error> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            overwrite_tree(&entry?.path(), purge, warnings)?;
+        }
+    } else if metadata.is_file() {
+        // Another hard link means the data is still in use elsewhere.
+        if metadata.nlink() > 1 {
+            warnings.push(format!(
+                "{} has other hard links; it was only unlinked",
+                path.display()
+            ));
+            return Ok(());
+        }
+        if metadata.permissions().readonly() {
+            let mode = metadata.permissions().mode() | 0o200;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        if let Some(warning) = overwrite_caveat(&file) {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        overwrite_file(&mut file, metadata.len(), purge)?;
+    }
+    Ok(())
+}
+
+/// Writes `passes` passes of the pattern over the first `len` bytes, then
+/// truncates the file, syncing after each step.
+fn overwrite_file(file: &mut std::fs::File, len: u64, purge: &Purge) -> Result<(), std::io::Error> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    for _ in 0..purge.passes {
+        file.rewind()?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buffer.len() as u64) as usize;
+            if purge.pattern == Pattern::Random {
+                crate::crypto::rand_bytes(&mut buffer[..n]);
+            }
+            file.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+        file.sync_data()?;
+    }
+    file.set_len(0)?;
+    file.sync_all()
+}
+
+/// Explains why overwriting a file in place may leave its old contents on
+/// disk, judging by the file system it lives on.
+#[cfg(target_os = "linux")]
+fn overwrite_caveat(file: &std::fs::File) -> Option<String> {
+    use std::os::unix::io::AsRawFd;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Some(
+            "can't tell which file system the trash is on; overwriting may be ineffective"
+                .to_string(),
+        );
+    }
+    let name = match stat.f_type as u32 {
+        0x9123683e => "btrfs",
+        0x2fc12fc1 => "ZFS",
+        0xca451a4e => "bcachefs",
+        0xf2f52010 => "F2FS",
+        0x3434 => "NILFS",
+        0x6969 => {
+            return Some(
+                "the trash is on NFS; whether overwriting reaches the disk is up to the server"
+                    .to_string(),
+            )
+        }
+        0xff534d42 | 0xfe534d42 => {
+            return Some(
+                "the trash is on SMB; whether overwriting reaches the disk is up to the server"
+                    .to_string(),
+            )
+        }
+        _ => return None,
+    };
+    Some(format!(
+        "the trash is on {}, which writes new data elsewhere instead of in place; overwriting did not destroy the old contents",
+        name
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn overwrite_caveat(_file: &std::fs::File) -> Option<String> {
+    Some(
+        "can't tell whether this file system overwrites in place; overwriting may be ineffective"
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_purge_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::Config {
+            purge_mode: PurgeMode::Shred,
+            purge_passes: Some(2),
+            ..Default::default()
+        };
+        let shred = Purge::from_config(&config);
+        assert_eq!(shred.passes, 2);
+        assert_eq!(shred.pattern, Pattern::Random);
+
+        let tree = dir.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::write(tree.join("sub/secret"), "hunter2").unwrap();
+        std::fs::set_permissions(
+            tree.join("sub/secret"),
+            std::fs::Permissions::from_mode(0o400),
+        )
+        .unwrap();
+        purge(&tree, &shred).unwrap();
+        assert!(!tree.exists());
+        assert!(purge(&tree, &shred).unwrap().is_empty());
+
+        // a file with another link is left intact
+        let file = dir.path().join("file");
+        let link = dir.path().join("link");
+        std::fs::write(&file, "still needed").unwrap();
+        std::fs::hard_link(&file, &link).unwrap();
+        let warnings = purge(&file, &shred).unwrap();
+        assert!(warnings.iter().any(|w| w.contains("hard links")));
+        assert_eq!(std::fs::read_to_string(&link).unwrap(), "still needed");
+
+        let mut handle = std::fs::OpenOptions::new().write(true).open(&link).unwrap();
+        overwrite_file(&mut handle, 12, &Purge::from_config(&Default::default())).unwrap();
+        assert_eq!(std::fs::metadata(&link).unwrap().len(), 0);
+    }
+}
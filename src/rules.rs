@@ -0,0 +1,314 @@
+//! Per-path retention rules
+
+use crate::compression::Compression;
+use crate::fs::FileMetadata;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// What a rule does with the files it matches
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Keep them in the trash, for the rule's `ttl`
+    #[default]
+    Trash,
+    /// Never trash them, deleting them for good right away
+    Delete,
+}
+
+/// One entry of the `rules` setting. A rule applies to a file when all of
+/// its conditions hold; a rule without conditions applies to every file.
+/// Patterns and owners are checked as the configuration is loaded.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glob: Option<Glob>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<Regex>,
+    /// Only files (or directory trees) at least this many bytes big
+    #[serde(
+        default,
+        deserialize_with = "crate::human::de::option_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_size: Option<u64>,
+    /// Only files (or directory trees) at most this many bytes big
+    #[serde(
+        default,
+        deserialize_with = "crate::human::de::option_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+    /// How long to keep matching files instead of the global `ttl`
+    #[serde(
+        default,
+        deserialize_with = "crate::human::de::option_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ttl: Option<u64>,
+    /// Compression for matching files instead of the global setting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+/// Returns the first of `rules` which applies to the file described by
+/// `meta`, which must not be sealed yet.
+pub fn find<'a>(rules: &'a [Rule], meta: &FileMetadata) -> Option<&'a Rule> {
+    rules.iter().find(|rule| rule.matches(meta))
+}
+
+impl Rule {
+    pub fn matches(&self, meta: &FileMetadata) -> bool {
+        let path = Path::new(&meta.original_path);
+        self.glob
+            .as_ref()
+            .is_none_or(|glob| glob.matches(path, meta.is_dir))
+            && self
+                .regex
+                .as_ref()
+                .is_none_or(|regex| regex.compiled.is_match(&meta.original_path))
+            && self.min_size.is_none_or(|min| meta.file_size >= min)
+            && self.max_size.is_none_or(|max| meta.file_size <= max)
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| owner.uid == meta.uid)
+    }
+}
+
+/// A shell-style pattern. Without a `/` it is matched against the file name,
+/// like `*.o`; otherwise against the whole path, with `**` spanning
+/// directories and a leading `~/` standing for the home directory. A
+/// trailing `/` only matches directories.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Glob {
+    source: String,
+    compiled: glob::Pattern,
+    dirs_only: bool,
+    whole_path: bool,
+}
+
+impl Glob {
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        if self.whole_path {
+            self.compiled.matches_path_with(path, options)
+        } else {
+            path.file_name()
+                .is_some_and(|name| self.compiled.matches_with(&name.to_string_lossy(), options))
+        }
+    }
+}
+
+impl std::str::FromStr for Glob {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Glob, String> {
+        let (pattern, dirs_only) = match source.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (source, false),
+        };
+        let pattern = match (pattern.strip_prefix("~/"), dirs_next::home_dir()) {
+            (Some(rest), Some(home)) => format!(
+                "{}/{}",
+                glob::Pattern::escape(&home.to_string_lossy()),
+                rest
+            ),
+            _ => pattern.to_string(),
+        };
+        Ok(Glob {
+            source: source.to_string(),
+            compiled: glob::Pattern::new(&pattern).map_err(|e| invalid("glob", source, e))?,
+            dirs_only,
+            whole_path: pattern.contains('/'),
+        })
+    }
+}
+
+/// A regular expression searched for in the whole path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Regex {
+    compiled: regex::Regex,
+}
+
+impl std::str::FromStr for Regex {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Regex, String> {
+        regex::Regex::new(source)
+            .map(|compiled| Regex { compiled })
+            .map_err(|e| invalid("regex", source, e))
+    }
+}
+
+/// Only files owned by this user, given by name or uid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Owner {
+    source: String,
+    uid: u32,
+}
+
+impl std::str::FromStr for Owner {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Owner, String> {
+        let uid = uid_of(source).ok_or_else(|| invalid("owner", source, "no such user"))?;
+        Ok(Owner {
+            source: source.to_string(),
+            uid,
+        })
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Glob, String> {
+        s.parse()
+    }
+}
+
+impl From<Glob> for String {
+    fn from(glob: Glob) -> String {
+        glob.source
+    }
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Glob) -> bool {
+        self.source == other.source
+    }
+}
+
+impl TryFrom<String> for Regex {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Regex, String> {
+        s.parse()
+    }
+}
+
+impl From<Regex> for String {
+    fn from(regex: Regex) -> String {
+        regex.compiled.as_str().to_string()
+    }
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Regex) -> bool {
+        self.compiled.as_str() == other.compiled.as_str()
+    }
+}
+
+impl TryFrom<String> for Owner {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Owner, String> {
+        s.parse()
+    }
+}
+
+impl From<Owner> for String {
+    fn from(owner: Owner) -> String {
+        owner.source
+    }
+}
+
+fn uid_of(owner: &str) -> Option<u32> {
+    if let Ok(uid) = owner.parse() {
+        return Some(uid);
+    }
+    let name = std::ffi::CString::new(owner).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+    Some(unsafe { (*passwd).pw_uid })
+}
+
+fn invalid(what: &str, value: &str, e: impl std::fmt::Display) -> String {
+    format!("invalid {} '{}' in rules: {}", what, value, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn meta(path: &str, is_dir: bool, file_size: u64) -> FileMetadata {
+        FileMetadata {
+            original_path: path.to_string(),
+            file_size,
+            is_dir,
+            link_target: None,
+            blake3sum: String::new(),
+            mtime: 0,
+            atime: 0,
+            unix_mode: 0o644,
+            uid: 1000,
+            gid: 1000,
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            r#"
+- glob: "*.o"
+  ttl: 86400
+- glob: target/
+  ttl: 86400
+- glob: /home/*/Documents/**
+  ttl: 7776000
+- regex: '\.(log|out)$'
+  min_size: 1000
+  compression: { codec: zstd }
+- owner: "0"
+  action: delete
+"#,
+        )
+        .unwrap();
+        let ttl = |m: &FileMetadata| find(&rules, m).and_then(|r| r.ttl);
+        assert_eq!(ttl(&meta("/src/build/main.o", false, 10)), Some(86400));
+        assert_eq!(ttl(&meta("/src/rim/target", true, 10)), Some(86400));
+        assert_eq!(ttl(&meta("/src/rim/target", false, 10)), None);
+        assert_eq!(
+            ttl(&meta("/home/zelly/Documents/taxes/2025.pdf", false, 10)),
+            Some(7776000)
+        );
+        assert_eq!(ttl(&meta("/home/zelly/Downloads/a.pdf", false, 10)), None);
+
+        let log = find(&rules, &meta("/var/tmp/big.log", false, 5000));
+        assert!(log.is_some_and(|r| r.compression.is_some()));
+        assert!(find(&rules, &meta("/var/tmp/small.log", false, 50)).is_none());
+
+        let mut root = meta("/tmp/x", false, 1);
+        root.uid = 0;
+        let rule = find(&rules, &root).unwrap();
+        assert_eq!(rule.action, Action::Delete);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in ["regex: '('", "glob: '[a'", "owner: no-such-user-here"] {
+            let err = serde_yaml::from_str::<Rule>(rule).unwrap_err();
+            assert!(err.to_string().contains("in rules"), "{}", err);
+        }
+        let rule: Rule = serde_yaml::from_str("glob: target/\nregex: '\\.o$'").unwrap();
+        let yaml = serde_yaml::to_string(&rule).unwrap();
+        assert_eq!(serde_yaml::from_str::<Rule>(&yaml).unwrap(), rule);
+    }
+}
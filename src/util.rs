@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
+#[allow(dead_code)]
 pub fn toposort_files(files: &Vec<PathBuf>) -> Vec<PathBuf> {
     let mut graph: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
     for file in files {
@@ -51,7 +52,7 @@ mod test {
 
     #[test]
     fn test_toposort_files() {
-        let mut files = vec![
+        let files = vec![
             PathBuf::from("/tmp"),
             PathBuf::from("/tmp/foo/bar/baz/quux"),
             PathBuf::from("/tmp/foo"),
@@ -0,0 +1,312 @@
+//! Runs real programs under the LD_PRELOAD library and checks which of
+//! their deletions end up in the trash.
+
+#![cfg(rim_preload)]
+
+use rim::{config::Config, metadata_db::ListFilter, App};
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    rc::Rc,
+};
+
+/// A trash under `root/trash`, with `root/tmp` excluded from it, and a
+/// `root/home` to delete things from
+struct Fixture {
+    _dir: tempfile::TempDir,
+    root: PathBuf,
+    config: PathBuf,
+    library: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for sub in ["home", "tmp/sub", "trash", "cache"] {
+            std::fs::create_dir_all(root.join(sub)).unwrap();
+        }
+        let config = root.join("rim.yaml");
+        Config {
+            trashdir: root.join("trash"),
+            preload_exclude: vec![root.join("tmp")],
+            ..Default::default()
+        }
+        .save(&config)
+        .unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_rim"))
+            .arg("--config")
+            .arg(&config)
+            .arg("preload")
+            .env("XDG_CACHE_HOME", root.join("cache"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let library = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+        Fixture {
+            _dir: dir,
+            root,
+            config,
+            library,
+        }
+    }
+
+    /// `program` with the library preloaded, the way rim-wrap runs it,
+    /// from `root/home`
+    fn command(&self, program: &str) -> Command {
+        let mut command = Command::new(program);
+        command
+            .current_dir(self.root.join("home"))
+            .env("LD_PRELOAD", &self.library)
+            .env("RIM_BIN", env!("CARGO_BIN_EXE_rim"))
+            .env("RIM_CONFIG", &self.config)
+            .env("RIM_PRELOAD_EXCLUDE", self.root.join("tmp"))
+            .env_remove("RIM_INTERCEPTING")
+            .env_remove("RIM_OPERATION")
+            .env_remove("RIM_PRELOAD_OVERWRITES");
+        command
+    }
+
+    fn write(&self, path: &str, contents: &str) -> PathBuf {
+        let path = self.root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn app(&self) -> App {
+        App::new(Rc::new(Config::open(&self.config).unwrap())).unwrap()
+    }
+
+    /// Original paths of everything in the trash, relative to `root`
+    fn trashed(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self
+            .app()
+            .list(&ListFilter::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                Path::new(&e.metadata.original_path)
+                    .strip_prefix(&self.root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+fn run(command: &mut Command) -> Output {
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+#[test]
+fn test_unlink_rmdir() {
+    let fixture = Fixture::new();
+    let file = fixture.write("home/a.txt", "a");
+    std::fs::create_dir(fixture.root.join("home/empty")).unwrap();
+    run(fixture.command("unlink").arg("a.txt"));
+    run(fixture.command("rmdir").arg("empty"));
+    assert!(!file.exists());
+    assert!(!fixture.root.join("home/empty").exists());
+    assert_eq!(fixture.trashed(), ["home/a.txt", "home/empty"]);
+
+    // rm -r empties directories with unlinkat(2), then removes them with
+    // unlinkat(2) and AT_REMOVEDIR
+    fixture.write("home/tree/one", "1");
+    fixture.write("home/tree/sub/two", "2");
+    run(fixture.command("rm").args(["-r", "tree"]));
+    assert!(!fixture.root.join("home/tree").exists());
+    assert_eq!(
+        fixture.trashed(),
+        [
+            "home/a.txt",
+            "home/empty",
+            "home/tree",
+            "home/tree/one",
+            "home/tree/sub",
+            "home/tree/sub/two"
+        ]
+    );
+
+    // errors from rim are the call's
+    fixture.write("home/full/f", "f");
+    let output = fixture.command("rmdir").arg("full").output().unwrap();
+    assert!(!output.status.success());
+    assert!(fixture.root.join("home/full/f").exists());
+    assert_eq!(fixture.trashed().len(), 6);
+}
+
+#[test]
+fn test_excluded() {
+    let fixture = Fixture::new();
+    let file = fixture.write("tmp/scratch", "x");
+    run(fixture.command("rm").arg(&file));
+    assert!(!file.exists());
+    assert!(fixture.trashed().is_empty());
+
+    // `..` can't lead out of an excluded directory...
+    let file = fixture.write("home/work/b.txt", "b");
+    run(fixture
+        .command("rm")
+        .arg("../../home/work/b.txt")
+        .current_dir(fixture.root.join("tmp/sub")));
+    assert!(!file.exists());
+    assert_eq!(fixture.trashed(), ["home/work/b.txt"]);
+
+    // ...nor into one
+    let file = fixture.write("tmp/c.txt", "c");
+    run(fixture.command("rm").arg("../tmp/c.txt"));
+    assert!(!file.exists());
+    assert_eq!(fixture.trashed(), ["home/work/b.txt"]);
+
+    // and neither can a symbolic link to a directory
+    std::os::unix::fs::symlink(fixture.root.join("tmp"), fixture.root.join("home/t")).unwrap();
+    let file = fixture.write("tmp/d.txt", "d");
+    run(fixture.command("rm").arg("t/d.txt"));
+    assert!(!file.exists());
+    assert_eq!(fixture.trashed(), ["home/work/b.txt"]);
+
+    // rim itself resolves the path too, as the library's exclusions are
+    // only a shortcut
+    let file = fixture.write("home/work/e.txt", "e");
+    let status = Command::new(env!("CARGO_BIN_EXE_rim"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .arg("intercept")
+        .arg("--")
+        .arg(fixture.root.join("tmp/sub/../../home/work/e.txt"))
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+    assert!(!file.exists());
+    assert_eq!(fixture.trashed(), ["home/work/b.txt", "home/work/e.txt"]);
+    let status = Command::new(env!("CARGO_BIN_EXE_rim"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .arg("intercept")
+        .arg("--")
+        .arg(fixture.root.join("home/t/d.txt"))
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(rim::preload::PASS));
+}
+
+#[test]
+fn test_never_prompts() {
+    let fixture = Fixture::new();
+    // encryption with neither a keyring nor a key file would have to ask
+    // for a passphrase, so the deletion goes ahead instead
+    let mut config = Config::open(&fixture.config).unwrap();
+    config.encryption = Some(Default::default());
+    config.save(&fixture.config).unwrap();
+    let file = fixture.write("home/a.txt", "a");
+    run(fixture
+        .command("rm")
+        .arg("a.txt")
+        .env_remove("RIM_PASSPHRASE"));
+    assert!(!file.exists());
+    assert!(!config.keyring_path().exists());
+
+    // given the passphrase, it sets the keyring up
+    let file = fixture.write("home/b.txt", "b");
+    run(fixture
+        .command("rm")
+        .arg("b.txt")
+        .env("RIM_PASSPHRASE", "not a very secret passphrase"));
+    assert!(!file.exists());
+    assert!(config.keyring_path().exists());
+    assert_eq!(fixture.app().list(&ListFilter::default()).unwrap().len(), 1);
+}
+
+#[test]
+fn test_wrap() {
+    let fixture = Fixture::new();
+    fixture.write("home/tree/one", "1");
+    fixture.write("home/tree/sub/two", "2");
+    fixture.write("home/other", "3");
+    let output = run(Command::new(env!("CARGO_BIN_EXE_rim-wrap"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .args(["--", "rm", "-rf", "tree"])
+        .current_dir(fixture.root.join("home"))
+        .env("XDG_CACHE_HOME", fixture.root.join("cache")));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("'rm -rf tree' put 4 entries"), "{}", stderr);
+    assert!(stderr.contains("rim-recover --operation 1"), "{}", stderr);
+    assert!(!fixture.root.join("home/tree").exists());
+
+    // deleted without rim-wrap, so not part of the operation
+    run(fixture.command("rm").arg("other"));
+    run(Command::new(env!("CARGO_BIN_EXE_rim-recover"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .args(["--operation", "1"]));
+    assert_eq!(
+        std::fs::read_to_string(fixture.root.join("home/tree/sub/two")).unwrap(),
+        "2"
+    );
+    assert_eq!(
+        std::fs::read_to_string(fixture.root.join("home/tree/one")).unwrap(),
+        "1"
+    );
+    assert!(!fixture.root.join("home/other").exists());
+    assert_eq!(fixture.trashed(), ["home/other"]);
+}
+
+#[test]
+fn test_overwrites() {
+    let fixture = Fixture::new();
+    let old = fixture.write("home/old", "old");
+    fixture.write("home/new", "new");
+    run(fixture
+        .command("mv")
+        .args(["new", "old"])
+        .env("RIM_PRELOAD_OVERWRITES", "1"));
+    assert_eq!(std::fs::read_to_string(&old).unwrap(), "new");
+    fixture.write("home/copy", "copied");
+    run(fixture
+        .command("cp")
+        .args(["copy", "old"])
+        .env("RIM_PRELOAD_OVERWRITES", "1"));
+    assert_eq!(std::fs::read_to_string(&old).unwrap(), "copied");
+    let contents = |entry: &rim::metadata_db::TrashEntry| std::fs::read(&entry.trash_path).unwrap();
+    let entries = fixture.app().list(&ListFilter::default()).unwrap();
+    assert_eq!(
+        entries.iter().map(contents).collect::<Vec<_>>(),
+        [b"new".to_vec(), b"old".to_vec()]
+    );
+
+    // without RIM_PRELOAD_OVERWRITES, only deletions are kept
+    run(fixture.command("sh").args(["-c", "echo lost > old"]));
+    assert_eq!(fixture.trashed().len(), 2);
+
+    // recovering the operation brings back the file as it was before
+    run(Command::new(env!("CARGO_BIN_EXE_rim-wrap"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .args(["--overwrites", "--"])
+        .args([
+            "sh",
+            "-c",
+            "echo two > old; echo three > old; echo x > new; mv new old; rm old",
+        ])
+        .current_dir(fixture.root.join("home"))
+        .env("XDG_CACHE_HOME", fixture.root.join("cache")));
+    assert!(!old.exists());
+    let operation = ListFilter {
+        operation: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(fixture.app().list(&operation).unwrap().len(), 4);
+    run(Command::new(env!("CARGO_BIN_EXE_rim-recover"))
+        .arg("--config")
+        .arg(&fixture.config)
+        .args(["--operation", "1"]));
+    assert_eq!(std::fs::read_to_string(&old).unwrap(), "lost\n");
+    assert_eq!(fixture.app().list(&operation).unwrap().len(), 3);
+}